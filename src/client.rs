@@ -1,16 +1,19 @@
 #[cfg(feature = "json_parsing")]
-use crate::util::{FromJson, FromJsonError, IntoJson};
+use crate::util::IntoJson;
 #[cfg(feature = "json_parsing")]
 use json::{object, JsonValue};
 
-use crate::util::{ClientCommand, Command};
+use crate::util::{ClientCommand, Color, Command, CommandError};
 
-use super::{id, Channel, Nickname, Password, ServerApi, SessionId, Text};
+#[cfg(feature = "json_parsing")]
+use super::{id, PasswordStyle};
+use super::{Channel, Hash, Nickname, Password, ServerApi, SessionId, Text, UserId, Users};
 
 /// V2 Specific
 /// Sent to the server before even joining the channel.
 /// Server replies back with `server::Session`.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Session {
     /// Whether or not this client is a bot.
     pub is_bot: bool,
@@ -18,6 +21,17 @@ pub struct Session {
     /// Currently unsupported on the server, but it exists.
     pub id: Option<SessionId>,
 }
+impl Session {
+    /// The serialized form of the command, when it doesn't need to be built at runtime.
+    /// That is only the case without a session id, and it is the same for every server api.
+    pub fn to_static_str(&self) -> Option<&'static str> {
+        match (self.is_bot, &self.id) {
+            (false, None) => Some(r#"{"cmd":"session","isBot":false}"#),
+            (true, None) => Some(r#"{"cmd":"session","isBot":true}"#),
+            (_, Some(_)) => None,
+        }
+    }
+}
 impl Command for Session {
     const CMD: &'static str = "session";
 }
@@ -37,6 +51,7 @@ impl IntoJson for Session {
 
 /// Command for joining a channel.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Join {
     /// The nickname that you wish to join as. Usually it is what you get.
     pub nick: Nickname,
@@ -47,10 +62,47 @@ pub struct Join {
     /// That is handled by the `IntoJson` method.
     pub password: Option<Password>,
 }
+impl Join {
+    /// Create a join with a password, which the server hashes into the trip shown beside our
+    /// nick. The same password always gives the same trip, so a bot can keep its identity by
+    /// storing it.
+    /// Where the password goes depends on the api, see `ServerApi::password_style`:
+    /// - V2 and PreV2 send it in its own `pass` field.
+    /// - Legacy appends it to the nick, as `nick#password`.
+    pub fn with_trip_password(nick: Nickname, channel: Channel, password: Password) -> Join {
+        Join {
+            nick,
+            channel,
+            password: Some(password),
+        }
+    }
+
+    /// Create a join for retrying after our nick was taken, by appending `attempt` underscores.
+    /// The original nick is cut short if needed to stay within the api's `max_nick` limit.
+    pub fn with_nick_suffix(&self, attempt: u32, server_api: ServerApi) -> Join {
+        let max_nick = server_api.limits().max_nick;
+        let suffix_len = (attempt as usize).min(max_nick);
+        let mut nick: Nickname = self.nick.chars().take(max_nick - suffix_len).collect();
+        nick.push_str(&"_".repeat(suffix_len));
+        Join {
+            nick,
+            ..self.clone()
+        }
+    }
+}
 impl Command for Join {
     const CMD: &'static str = "join";
 }
-impl ClientCommand for Join {}
+impl ClientCommand for Join {
+    fn check(&self, server_api: ServerApi, errors: &mut Vec<CommandError>) {
+        if !is_valid_nick(&self.nick, server_api) {
+            errors.push(CommandError::InvalidNick);
+        }
+        if self.channel.chars().count() > server_api.limits().max_channel {
+            errors.push(CommandError::ChannelTooLong);
+        }
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl IntoJson for Join {
     fn into_json(mut self, server_api: ServerApi) -> JsonValue {
@@ -61,10 +113,9 @@ impl IntoJson for Join {
         // We don't set nick early on as password can modify it
         value[id::CHANNEL] = self.channel.into();
         if let Some(password) = self.password {
-            match server_api {
-                // TODO: should this be hackchatprev2? its relatively recent...
-                ServerApi::HackChatV2 | ServerApi::HackChatPreV2 => value[PASS] = password.into(),
-                ServerApi::HackChatLegacy => {
+            match server_api.password_style() {
+                PasswordStyle::SeparateField => value[PASS] = password.into(),
+                PasswordStyle::NickSuffix => {
                     // Format is 'nick#password' for legacy servers
                     self.nick.push('#');
                     self.nick.push_str(&password);
@@ -76,28 +127,901 @@ impl IntoJson for Join {
     }
 }
 
+/// Change your nickname while staying in the channel.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChangeNick {
+    /// The nickname to change to.
+    pub nick: Nickname,
+}
+impl Command for ChangeNick {
+    const CMD: &'static str = "changenick";
+}
+impl ClientCommand for ChangeNick {
+    fn check(&self, server_api: ServerApi, errors: &mut Vec<CommandError>) {
+        if !is_valid_nick(&self.nick, server_api) {
+            errors.push(CommandError::InvalidNick);
+        }
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for ChangeNick {
+    /// Legacy servers have no way to change nick and ignore this, but it is still created.
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::NICK] = self.nick.into();
+        value
+    }
+}
+
+/// Whether the server would accept the nick: letters, digits, or underscores, up to the api's
+/// limit.
+pub fn is_valid_nick(nick: &str, server_api: ServerApi) -> bool {
+    let len = nick.chars().count();
+    len > 0
+        && len <= server_api.limits().max_nick
+        && nick.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 /// Tells the server that you wish to send a message.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Chat {
     /// Only needed on V2 as it desires to have multi-channel support.
     pub channel: Option<Channel>,
     /// The text that is sent.
     pub text: Text,
 }
+impl Chat {
+    /// Create a chat message, refusing text that is empty or only whitespace since the server
+    /// would just drop it.
+    pub fn new(text: Text, channel: Option<Channel>) -> Result<Chat, CommandError> {
+        if text.trim().is_empty() {
+            return Err(CommandError::EmptyText);
+        }
+
+        Ok(Chat { channel, text })
+    }
+
+    /// Create a chat message for the channel that we're in, which is the channel of our own
+    /// user in `users`. If that isn't known the channel is left as `None`, which `check` reports
+    /// as missing on V2.
+    pub fn in_current(text: Text, users: &Users) -> Chat {
+        let channel = users
+            .ourself()
+            .and_then(|id| users.get(id))
+            .and_then(|info| info.channel.clone());
+        Chat { channel, text }
+    }
+
+    /// Remove whitespace from the start and end of the text, which some servers strip or
+    /// reject. Whitespace within the text is kept.
+    pub fn trim(mut self) -> Chat {
+        let trimmed = self.text.trim();
+        if trimmed.len() != self.text.len() {
+            self.text = trimmed.to_owned();
+        }
+        self
+    }
+
+    /// Shorten every run of newlines in the text to at most `max_consecutive`, since the server
+    /// limits how many blank lines a message can have.
+    pub fn normalize_newlines(mut self, max_consecutive: usize) -> Chat {
+        let mut text = Text::with_capacity(self.text.len());
+        let mut run = 0;
+        for c in self.text.chars() {
+            if c == '\n' {
+                run += 1;
+                if run > max_consecutive {
+                    continue;
+                }
+            } else {
+                run = 0;
+            }
+            text.push(c);
+        }
+        self.text = text;
+        self
+    }
+
+    /// Html-escape the text, but only if the server api expects the client to do so, see
+    /// `ServerApi::expects_escaped_text`. Otherwise the chat is returned unchanged, so that it
+    /// isn't escaped twice.
+    pub fn escaped(mut self, server_api: ServerApi) -> Chat {
+        if server_api.expects_escaped_text() {
+            self.text = crate::util::escape_entities(&self.text);
+        }
+        self
+    }
+}
 impl Command for Chat {
     const CMD: &'static str = "chat";
 }
-impl ClientCommand for Chat {}
+impl ClientCommand for Chat {
+    fn check(&self, server_api: ServerApi, errors: &mut Vec<CommandError>) {
+        if self.text.trim().is_empty() {
+            errors.push(CommandError::EmptyText);
+        } else if self.text.chars().count() > server_api.limits().max_text {
+            errors.push(CommandError::TextTooLong);
+        }
+
+        if server_api == ServerApi::HackChatV2 && self.channel.is_none() {
+            errors.push(CommandError::MissingChannel);
+        }
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl IntoJson for Chat {
     fn into_json(self, server_api: ServerApi) -> JsonValue {
         let mut value = object! {};
         value[id::CMD] = Self::CMD.into();
         value[id::TEXT] = self.text.into();
-        if let ServerApi::HackChatV2 = server_api {
-            value[id::CHANNEL] = self.channel.into();
+        if let (ServerApi::HackChatV2, Some(channel)) = (server_api, self.channel) {
+            value[id::CHANNEL] = channel.into();
+        }
+
+        value
+    }
+}
+
+/// Keepalive sent to the server, which may reply with `server::Pong`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ping;
+impl Ping {
+    /// The serialized form of the command, which is the same for every server api.
+    pub const WIRE: &'static str = r#"{"cmd":"ping"}"#;
+
+    pub fn to_static_str(&self) -> &'static str {
+        Self::WIRE
+    }
+}
+impl Command for Ping {
+    const CMD: &'static str = "ping";
+}
+impl ClientCommand for Ping {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Ping {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value
+    }
+}
+
+/// Ask the server for statistics about itself. Server replies back with `server::Stats`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stats;
+impl Command for Stats {
+    const CMD: &'static str = "stats";
+}
+impl ClientCommand for Stats {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Stats {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value
+    }
+}
+
+/// V2 Specific
+/// Change the color that your nick is displayed in.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChangeColor {
+    pub color: Color,
+}
+impl ChangeColor {
+    /// Whether the color is within what the server allows.
+    /// Servers don't tell us their policy, so this is only useful for warning before sending.
+    pub fn is_allowed(&self, policy: &ColorPolicy) -> bool {
+        let luminance = self.color.luminance();
+        luminance >= policy.min_luminance && luminance <= policy.max_luminance
+    }
+}
+impl Command for ChangeColor {
+    const CMD: &'static str = "changecolor";
+}
+impl ClientCommand for ChangeColor {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for ChangeColor {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::COLOR] = self.color.to_hex().into();
+        value
+    }
+}
+
+/// The range of colors that a server accepts, to keep nicks readable.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColorPolicy {
+    /// The lowest relative luminance allowed, from 0 (black) to 1 (white).
+    pub min_luminance: f32,
+    /// The highest relative luminance allowed, from 0 (black) to 1 (white).
+    pub max_luminance: f32,
+}
+
+/// Invite a user to another channel.
+/// Server replies back with `server::Invite` (or an `Info` on older servers).
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Invite {
+    /// The nick of the user to invite. Used by legacy servers, and by V2 when there is no id.
+    pub nick: Option<Nickname>,
+    /// The id of the user to invite. Preferred by V2.
+    pub user_id: Option<UserId>,
+    /// The channel to invite them to. If there is none then the server picks a random one.
+    pub channel: Option<Channel>,
+}
+impl Command for Invite {
+    const CMD: &'static str = "invite";
+}
+impl ClientCommand for Invite {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Invite {
+    fn into_json(self, server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        match (server_api, self.user_id, self.nick) {
+            // V2 identifies users by id, which can't be ambiguous like nicks can
+            (ServerApi::HackChatV2, Some(user_id), _) => value[id::USER_ID] = user_id.into(),
+            (_, _, Some(nick)) => value[id::NICK] = nick.into(),
+            (_, _, None) => {}
+        }
+        if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        value
+    }
+}
+
+/// Moderator command to ban a user, by nick.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ban {
+    pub nick: Nickname,
+}
+impl Command for Ban {
+    const CMD: &'static str = "ban";
+}
+impl ClientCommand for Ban {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Ban {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::NICK] = self.nick.into();
+        value
+    }
+}
+
+/// Who to unban.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UnbanTarget {
+    /// The hash of their ip, as the server shows it.
+    Hash(Hash),
+    /// Their ip address.
+    Ip(String),
+}
+
+/// Moderator command to lift a ban.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Unban {
+    pub target: UnbanTarget,
+}
+impl Command for Unban {
+    const CMD: &'static str = "unban";
+}
+impl ClientCommand for Unban {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Unban {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        const IP: &str = "ip";
+
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        match self.target {
+            UnbanTarget::Hash(hash) => value[id::HASH] = hash.into(),
+            UnbanTarget::Ip(ip) => value[IP] = ip.into(),
         }
+        value
+    }
+}
 
+/// The users to kick.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KickTarget {
+    Single(Nickname),
+    /// V2 can kick several users at once.
+    Multiple(Vec<Nickname>),
+}
+
+/// Moderator command to kick users out of the channel.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Kick {
+    pub nick: KickTarget,
+}
+impl Command for Kick {
+    const CMD: &'static str = "kick";
+}
+impl ClientCommand for Kick {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Kick {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::NICK] = match self.nick {
+            KickTarget::Single(nick) => nick.into(),
+            KickTarget::Multiple(nicks) => nicks.into(),
+        };
+        value
+    }
+}
+
+/// V2 Specific
+/// Move to another channel without rejoining. Server replies back with
+/// `server::ChannelChanged`.
+/// Legacy and PreV2 servers don't support this, so rejoin with `Join` instead. The command is
+/// still created for them.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Move {
+    /// The channel to move to.
+    pub channel: Channel,
+}
+impl Command for Move {
+    const CMD: &'static str = "move";
+}
+impl ClientCommand for Move {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Move {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::CHANNEL] = self.channel.into();
         value
     }
 }
+
+/// Any of the commands that the client may send.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClientMessage {
+    Session(Session),
+    Join(Join),
+    ChangeNick(ChangeNick),
+    Chat(Chat),
+    Ping(Ping),
+    Stats(Stats),
+    ChangeColor(ChangeColor),
+    Invite(Invite),
+    Ban(Ban),
+    Unban(Unban),
+    Kick(Kick),
+    Move(Move),
+}
+impl ClientMessage {
+    /// The name of the command, as in its `cmd` field.
+    pub fn cmd_name(&self) -> &'static str {
+        match self {
+            ClientMessage::Session(_) => Session::CMD,
+            ClientMessage::Join(_) => Join::CMD,
+            ClientMessage::ChangeNick(_) => ChangeNick::CMD,
+            ClientMessage::Chat(_) => Chat::CMD,
+            ClientMessage::Ping(_) => Ping::CMD,
+            ClientMessage::Stats(_) => Stats::CMD,
+            ClientMessage::ChangeColor(_) => ChangeColor::CMD,
+            ClientMessage::Invite(_) => Invite::CMD,
+            ClientMessage::Ban(_) => Ban::CMD,
+            ClientMessage::Unban(_) => Unban::CMD,
+            ClientMessage::Kick(_) => Kick::CMD,
+            ClientMessage::Move(_) => Move::CMD,
+        }
+    }
+
+    /// Serialize the wrapped command, the same as calling its `IntoJson::into_json` directly.
+    #[cfg(feature = "json_parsing")]
+    pub fn to_json(self, server_api: ServerApi) -> JsonValue {
+        match self {
+            ClientMessage::Session(cmd) => cmd.into_json(server_api),
+            ClientMessage::Join(cmd) => cmd.into_json(server_api),
+            ClientMessage::ChangeNick(cmd) => cmd.into_json(server_api),
+            ClientMessage::Chat(cmd) => cmd.into_json(server_api),
+            ClientMessage::Ping(cmd) => cmd.into_json(server_api),
+            ClientMessage::Stats(cmd) => cmd.into_json(server_api),
+            ClientMessage::ChangeColor(cmd) => cmd.into_json(server_api),
+            ClientMessage::Invite(cmd) => cmd.into_json(server_api),
+            ClientMessage::Ban(cmd) => cmd.into_json(server_api),
+            ClientMessage::Unban(cmd) => cmd.into_json(server_api),
+            ClientMessage::Kick(cmd) => cmd.into_json(server_api),
+            ClientMessage::Move(cmd) => cmd.into_json(server_api),
+        }
+    }
+}
+/// A one line summary of the message, for logging.
+/// Passwords and session ids are left out, so that they don't end up in logs.
+impl std::fmt::Display for ClientMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.cmd_name())?;
+        match self {
+            ClientMessage::Session(session) => {
+                if session.is_bot {
+                    write!(f, " bot")?;
+                }
+                Ok(())
+            }
+            ClientMessage::Join(join) => write!(f, " {} #{}", join.nick, join.channel),
+            ClientMessage::ChangeNick(change_nick) => write!(f, " {}", change_nick.nick),
+            ClientMessage::Chat(chat) => match &chat.channel {
+                Some(channel) => write!(f, " #{}: {}", channel, chat.text),
+                None => write!(f, " {}", chat.text),
+            },
+            ClientMessage::Ping(_) | ClientMessage::Stats(_) => Ok(()),
+            ClientMessage::ChangeColor(change_color) => write!(f, " {}", change_color.color),
+            ClientMessage::Invite(invite) => {
+                match (&invite.nick, invite.user_id) {
+                    (Some(nick), _) => write!(f, " {}", nick)?,
+                    (None, Some(user_id)) => write!(f, " {}", user_id)?,
+                    (None, None) => {}
+                }
+                if let Some(channel) = &invite.channel {
+                    write!(f, " to #{}", channel)?;
+                }
+                Ok(())
+            }
+            ClientMessage::Ban(ban) => write!(f, " {}", ban.nick),
+            ClientMessage::Unban(unban) => match &unban.target {
+                UnbanTarget::Hash(hash) => write!(f, " hash {}", hash),
+                UnbanTarget::Ip(ip) => write!(f, " ip {}", ip),
+            },
+            ClientMessage::Kick(kick) => match &kick.nick {
+                KickTarget::Single(nick) => write!(f, " {}", nick),
+                KickTarget::Multiple(nicks) => write!(f, " {}", nicks.join(", ")),
+            },
+            ClientMessage::Move(move_) => write!(f, " #{}", move_.channel),
+        }
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for ClientMessage {
+    fn into_json(self, server_api: ServerApi) -> JsonValue {
+        self.to_json(server_api)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AccessUserId, MaybeExist, UserInfo, MAX_NICK_LENGTH};
+
+    #[test]
+    fn chat_new_refuses_empty_text() {
+        assert_eq!(
+            Chat::new(Text::new(), None).unwrap_err(),
+            CommandError::EmptyText
+        );
+        assert_eq!(
+            Chat::new(" \n\t".to_owned(), None).unwrap_err(),
+            CommandError::EmptyText
+        );
+
+        let chat = Chat::new("hello".to_owned(), Some("lounge".to_owned())).unwrap();
+        assert_eq!(chat.text, "hello");
+        assert_eq!(chat.channel.as_deref(), Some("lounge"));
+    }
+
+    #[test]
+    fn nick_suffix_appends_underscores() {
+        let join = Join {
+            nick: "alice".to_owned(),
+            channel: "lounge".to_owned(),
+            password: None,
+        };
+        let server_api = ServerApi::HackChatV2;
+        assert_eq!(join.with_nick_suffix(1, server_api).nick, "alice_");
+        assert_eq!(join.with_nick_suffix(3, server_api).nick, "alice___");
+        assert_eq!(join.with_nick_suffix(3, server_api).channel, "lounge");
+    }
+
+    #[test]
+    fn nick_suffix_truncates_to_fit() {
+        let join = Join {
+            nick: "a".repeat(MAX_NICK_LENGTH),
+            channel: "lounge".to_owned(),
+            password: None,
+        };
+        for server_api in [
+            ServerApi::HackChatV2,
+            ServerApi::HackChatPreV2,
+            ServerApi::HackChatLegacy,
+        ] {
+            let max_nick = server_api.limits().max_nick;
+            let retry = join.with_nick_suffix(2, server_api);
+            assert_eq!(retry.nick.len(), max_nick);
+            assert!(retry.nick.ends_with("a__"));
+
+            let retry = join.with_nick_suffix(1000, server_api);
+            assert_eq!(retry.nick, "_".repeat(max_nick));
+        }
+    }
+
+    /// One of every client command. The match below has no wildcard so that adding a variant to
+    /// `ClientMessage` without listing it here fails to compile.
+    #[cfg(feature = "json_parsing")]
+    fn every_client_message() -> Vec<ClientMessage> {
+        let messages = vec![
+            ClientMessage::Session(Session {
+                is_bot: false,
+                id: None,
+            }),
+            ClientMessage::Join(Join {
+                nick: "alice".to_owned(),
+                channel: "lounge".to_owned(),
+                password: Some("secret".to_owned()),
+            }),
+            ClientMessage::ChangeNick(ChangeNick {
+                nick: "bob".to_owned(),
+            }),
+            ClientMessage::Chat(Chat {
+                channel: Some("lounge".to_owned()),
+                text: "hi".to_owned(),
+            }),
+            ClientMessage::Ping(Ping),
+            ClientMessage::Stats(Stats),
+            ClientMessage::ChangeColor(ChangeColor { color: Color::RED }),
+            ClientMessage::Invite(Invite {
+                nick: Some("bob".to_owned()),
+                user_id: Some(2),
+                channel: None,
+            }),
+            ClientMessage::Ban(Ban {
+                nick: "bob".to_owned(),
+            }),
+            ClientMessage::Unban(Unban {
+                target: UnbanTarget::Hash("xyz".to_owned()),
+            }),
+            ClientMessage::Kick(Kick {
+                nick: KickTarget::Multiple(vec!["bob".to_owned(), "carol".to_owned()]),
+            }),
+            ClientMessage::Move(Move {
+                channel: "elsewhere".to_owned(),
+            }),
+        ];
+        for message in &messages {
+            match message {
+                ClientMessage::Session(_)
+                | ClientMessage::Join(_)
+                | ClientMessage::ChangeNick(_)
+                | ClientMessage::Chat(_)
+                | ClientMessage::Ping(_)
+                | ClientMessage::Stats(_)
+                | ClientMessage::ChangeColor(_)
+                | ClientMessage::Invite(_)
+                | ClientMessage::Ban(_)
+                | ClientMessage::Unban(_)
+                | ClientMessage::Kick(_)
+                | ClientMessage::Move(_) => {}
+            }
+        }
+        messages
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn every_command_sets_cmd() {
+        for server_api in [
+            ServerApi::HackChatV2,
+            ServerApi::HackChatPreV2,
+            ServerApi::HackChatLegacy,
+        ] {
+            for message in every_client_message() {
+                let cmd = message.cmd_name();
+                let json = message.into_json(server_api);
+                assert_eq!(json["cmd"].as_str(), Some(cmd), "{:?}", server_api);
+            }
+        }
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn chat_omits_missing_channel() {
+        let chat = Chat {
+            channel: None,
+            text: "hi".to_owned(),
+        };
+        let json = chat.into_json(ServerApi::HackChatV2);
+        assert!(!json.has_key("channel"));
+
+        let chat = Chat {
+            channel: Some("lounge".to_owned()),
+            text: "hi".to_owned(),
+        };
+        let json = chat.into_json(ServerApi::HackChatV2);
+        assert_eq!(json["channel"].as_str(), Some("lounge"));
+    }
+
+    #[test]
+    fn normalize_newlines_collapses_runs() {
+        let chat = Chat {
+            channel: None,
+            text: "a\n\n\n\n\nb\nc".to_owned(),
+        };
+        assert_eq!(chat.normalize_newlines(2).text, "a\n\nb\nc");
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn join_places_password() {
+        let join = Join {
+            nick: "alice".to_owned(),
+            channel: "lounge".to_owned(),
+            password: Some("secret".to_owned()),
+        };
+        let json = join.clone().into_json(ServerApi::HackChatV2);
+        assert_eq!(json["nick"].as_str(), Some("alice"));
+        assert_eq!(json["pass"].as_str(), Some("secret"));
+
+        let json = join.into_json(ServerApi::HackChatLegacy);
+        assert_eq!(json["nick"].as_str(), Some("alice#secret"));
+        assert!(!json.has_key("pass"));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn static_str_matches_into_json() {
+        for server_api in [
+            ServerApi::HackChatV2,
+            ServerApi::HackChatPreV2,
+            ServerApi::HackChatLegacy,
+        ] {
+            assert_eq!(Ping.to_static_str(), Ping.into_json(server_api).dump());
+            for is_bot in [false, true] {
+                let session = Session { is_bot, id: None };
+                assert_eq!(
+                    session.to_static_str().unwrap(),
+                    session.clone().into_json(server_api).dump()
+                );
+            }
+        }
+
+        let session = Session {
+            is_bot: false,
+            id: Some("abc".to_owned()),
+        };
+        assert_eq!(session.to_static_str(), None);
+    }
+
+    #[test]
+    fn chat_trim() {
+        let chat = Chat {
+            channel: None,
+            text: "  hi  ".to_owned(),
+        };
+        assert_eq!(chat.trim().text, "hi");
+
+        let chat = Chat {
+            channel: None,
+            text: "\n a\n\nb \n".to_owned(),
+        };
+        assert_eq!(chat.trim().text, "a\n\nb");
+    }
+
+    #[test]
+    fn change_color_policy() {
+        let policy = ColorPolicy {
+            min_luminance: 0.05,
+            max_luminance: 0.9,
+        };
+        assert!(ChangeColor { color: Color::RED }.is_allowed(&policy));
+        assert!(!ChangeColor {
+            color: Color::WHITE
+        }
+        .is_allowed(&policy));
+        assert!(!ChangeColor {
+            color: Color::BLACK
+        }
+        .is_allowed(&policy));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn change_nick_json() {
+        for server_api in [ServerApi::HackChatV2, ServerApi::HackChatLegacy] {
+            let json = ChangeNick {
+                nick: "bob".to_owned(),
+            }
+            .into_json(server_api);
+            assert_eq!(json["cmd"].as_str(), Some("changenick"));
+            assert_eq!(json["nick"].as_str(), Some("bob"));
+        }
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn change_color_json() {
+        let color = Color::rgb(1, 255, 16);
+        assert_eq!(color.to_hex(), "01ff10");
+
+        let json = ChangeColor { color }.into_json(ServerApi::HackChatV2);
+        assert_eq!(json["cmd"].as_str(), Some("changecolor"));
+        assert_eq!(json["color"].as_str(), Some("01ff10"));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn invite_json_per_api() {
+        let invite = Invite {
+            nick: Some("bob".to_owned()),
+            user_id: Some(2),
+            channel: Some("secret".to_owned()),
+        };
+
+        let json = invite.clone().into_json(ServerApi::HackChatV2);
+        assert_eq!(
+            json,
+            json::object! { cmd: "invite", userid: 2, channel: "secret" }
+        );
+
+        for server_api in [ServerApi::HackChatPreV2, ServerApi::HackChatLegacy] {
+            let json = invite.clone().into_json(server_api);
+            assert_eq!(
+                json,
+                json::object! { cmd: "invite", nick: "bob", channel: "secret" }
+            );
+        }
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn moderation_json() {
+        let api = ServerApi::HackChatV2;
+        assert_eq!(
+            Ban {
+                nick: "bob".to_owned()
+            }
+            .into_json(api),
+            json::object! { cmd: "ban", nick: "bob" }
+        );
+        assert_eq!(
+            Unban {
+                target: UnbanTarget::Hash("xyz".to_owned())
+            }
+            .into_json(api),
+            json::object! { cmd: "unban", hash: "xyz" }
+        );
+        assert_eq!(
+            Unban {
+                target: UnbanTarget::Ip("127.0.0.1".to_owned())
+            }
+            .into_json(api),
+            json::object! { cmd: "unban", ip: "127.0.0.1" }
+        );
+        assert_eq!(
+            Kick {
+                nick: KickTarget::Single("bob".to_owned())
+            }
+            .into_json(api),
+            json::object! { cmd: "kick", nick: "bob" }
+        );
+        assert_eq!(
+            Kick {
+                nick: KickTarget::Multiple(vec!["bob".to_owned(), "carol".to_owned()])
+            }
+            .into_json(api),
+            json::object! { cmd: "kick", nick: ["bob", "carol"] }
+        );
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn move_json() {
+        let json = Move {
+            channel: "elsewhere".to_owned(),
+        }
+        .into_json(ServerApi::HackChatV2);
+        assert_eq!(json, json::object! { cmd: "move", channel: "elsewhere" });
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn client_message_matches_direct_json() {
+        let chat = Chat {
+            channel: Some("lounge".to_owned()),
+            text: "hi".to_owned(),
+        };
+        for server_api in [ServerApi::HackChatV2, ServerApi::HackChatLegacy] {
+            assert_eq!(
+                ClientMessage::Chat(chat.clone()).to_json(server_api),
+                chat.clone().into_json(server_api)
+            );
+        }
+    }
+
+    #[test]
+    fn chat_escaped_only_when_expected() {
+        let chat = Chat {
+            channel: None,
+            text: "<b> & c".to_owned(),
+        };
+        assert_eq!(
+            chat.clone().escaped(ServerApi::HackChatLegacy).text,
+            "&lt;b&gt; &amp; c"
+        );
+        assert_eq!(
+            chat.clone().escaped(ServerApi::HackChatPreV2).text,
+            "<b> & c"
+        );
+        assert_eq!(chat.escaped(ServerApi::HackChatV2).text, "<b> & c");
+    }
+
+    #[test]
+    fn chat_in_current_channel() {
+        let mut users = Users::with_self(AccessUserId::Server(1));
+        assert_eq!(Chat::in_current("hi".to_owned(), &users).channel, None);
+
+        users.insert(
+            AccessUserId::Server(1),
+            UserInfo {
+                nick: "alice".to_owned(),
+                trip: MaybeExist::Unknown,
+                online: true,
+                channel: Some("lounge".to_owned()),
+                level: None,
+                color: None,
+                last_activity: None,
+            },
+        );
+        let chat = Chat::in_current("hi".to_owned(), &users);
+        assert_eq!(chat.channel.as_deref(), Some("lounge"));
+        assert_eq!(chat.text, "hi");
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn join_with_trip_password_per_api() {
+        let join =
+            Join::with_trip_password("alice".to_owned(), "lounge".to_owned(), "secret".to_owned());
+        for &api in &[ServerApi::HackChatV2, ServerApi::HackChatPreV2] {
+            assert_eq!(
+                join.clone().into_json(api),
+                json::object! { cmd: "join", channel: "lounge", pass: "secret", nick: "alice" }
+            );
+        }
+        assert_eq!(
+            join.into_json(ServerApi::HackChatLegacy),
+            json::object! { cmd: "join", channel: "lounge", nick: "alice#secret" }
+        );
+    }
+
+    #[test]
+    fn client_message_summary() {
+        let message = ClientMessage::Chat(Chat {
+            channel: Some("lounge".to_owned()),
+            text: "hello".to_owned(),
+        });
+        assert_eq!(message.to_string(), "chat #lounge: hello");
+
+        let message = ClientMessage::Join(Join {
+            nick: "alice".to_owned(),
+            channel: "lounge".to_owned(),
+            password: Some("secret".to_owned()),
+        });
+        assert_eq!(message.to_string(), "join alice #lounge");
+
+        let message = ClientMessage::Kick(Kick {
+            nick: KickTarget::Multiple(vec!["bob".to_owned(), "carol".to_owned()]),
+        });
+        assert_eq!(message.to_string(), "kick bob, carol");
+
+        assert_eq!(ClientMessage::Ping(Ping).to_string(), "ping");
+    }
+}
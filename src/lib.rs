@@ -1,18 +1,17 @@
-use std::{collections::HashMap, fmt::Display, num::ParseIntError};
+use std::{collections::HashMap, fmt::Display, num::ParseIntError, time::Duration};
 
 #[cfg(feature = "json_parsing")]
 use crate::util::FromJsonError;
 
-use util::MaybeExist;
+use util::{Color, MaybeExist};
 
 pub mod client;
 pub mod id;
 pub mod server;
 pub mod util;
 
-// TODO: provide a feature that disables the json
-
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ServerApi {
     /// Only partially supported as of this moment as the server is only partially implemented!
     HackChatV2,
@@ -22,22 +21,104 @@ pub enum ServerApi {
     HackChatLegacy,
 }
 
+impl ServerApi {
+    /// The websocket url of the main instance for each api.
+    /// These are all hack.chat itself, which has served each of the apis over its history.
+    pub fn default_endpoint(&self) -> &'static str {
+        match self {
+            ServerApi::HackChatV2 | ServerApi::HackChatPreV2 | ServerApi::HackChatLegacy => {
+                "wss://hack.chat/chat-ws"
+            }
+        }
+    }
+
+    /// The lengths that the server accepts, in characters.
+    /// These are currently the same for every api.
+    pub fn limits(&self) -> ApiLimits {
+        match self {
+            ServerApi::HackChatV2 | ServerApi::HackChatPreV2 | ServerApi::HackChatLegacy => {
+                ApiLimits {
+                    max_text: MAX_TEXT_LENGTH,
+                    max_nick: MAX_NICK_LENGTH,
+                    max_channel: MAX_CHANNEL_LENGTH,
+                }
+            }
+        }
+    }
+
+    /// How the password is sent when joining.
+    pub fn password_style(&self) -> PasswordStyle {
+        match self {
+            // TODO: should this be hackchatprev2? its relatively recent...
+            ServerApi::HackChatV2 | ServerApi::HackChatPreV2 => PasswordStyle::SeparateField,
+            ServerApi::HackChatLegacy => PasswordStyle::NickSuffix,
+        }
+    }
+
+    /// Whether chat text should be html-escaped by the client before it is sent.
+    /// Legacy servers relay the text as is to clients that put it straight into the page, while
+    /// PreV2 and V2 servers leave escaping to the receiving client, so escaping for them would
+    /// show up as literal entities.
+    pub fn expects_escaped_text(&self) -> bool {
+        match self {
+            ServerApi::HackChatV2 | ServerApi::HackChatPreV2 => false,
+            ServerApi::HackChatLegacy => true,
+        }
+    }
+}
+
+/// The longest text, nick, and channel, in characters, that a server accepts.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ApiLimits {
+    pub max_text: usize,
+    pub max_nick: usize,
+    pub max_channel: usize,
+}
+
+/// Where the password goes when joining a channel.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PasswordStyle {
+    /// In its own `pass` field.
+    SeparateField,
+    /// Appended to the nick, as `nick#password`.
+    NickSuffix,
+}
+
 /// PreV2/V2 hash of ip address
 pub type Hash = String;
 // TODO: make this zeroable?
 pub type Password = String;
 /// Note: this is not assured to be <= 24 characters.
 pub type Nickname = String;
+/// The longest nickname, in characters, that hack.chat will accept.
+pub const MAX_NICK_LENGTH: usize = 24;
 /// This channel should not have any question mark prefix from the way the website is accessed.
 pub type Channel = String;
+/// The longest channel name, in characters, that hack.chat will accept.
+pub const MAX_CHANNEL_LENGTH: usize = 120;
+/// Strip the question mark that the site puts in front of channel names, such as in `?lounge`.
+pub fn normalize_channel(channel: &str) -> &str {
+    channel.strip_prefix('?').unwrap_or(channel)
+}
 /// Note: This is not assured to be exactly 6 characters, because exotic hc instances may exist.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Trip(pub String);
 impl Display for Trip {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.fmt(f)
     }
 }
+impl PartialEq<str> for Trip {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+impl PartialEq<&str> for Trip {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
 impl Trip {
     #[cfg(feature = "json_parsing")]
     pub fn from_json(json: &mut json::JsonValue) -> MaybeExist<Trip> {
@@ -50,22 +131,147 @@ impl Trip {
         })
     }
 }
+impl MaybeExist<Trip> {
+    /// A single string holding all three states, for storage: the trip itself, `""` for `Not`,
+    /// and `"?"` for `Unknown`.
+    /// Trips never contain a question mark, so this can't be confused with an actual trip.
+    pub fn to_tristate(&self) -> &str {
+        match self {
+            MaybeExist::Has(trip) => &trip.0,
+            MaybeExist::Unknown => "?",
+            MaybeExist::Not => "",
+        }
+    }
+
+    /// The inverse of `to_tristate`.
+    pub fn from_tristate(text: &str) -> MaybeExist<Trip> {
+        match text {
+            "?" => MaybeExist::Unknown,
+            "" => MaybeExist::Not,
+            trip => MaybeExist::Has(Trip(trip.to_owned())),
+        }
+    }
+}
 pub type Text = String;
-/// Unix timestamp.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// The server limits the size of each message it receives, so text beyond this won't get through.
+pub const MAX_TEXT_LENGTH: usize = 65536;
+/// Unix timestamp, in milliseconds since the epoch, as the server sends its `time` fields.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Timestamp(pub u64);
 impl Timestamp {
+    pub fn from_millis(millis: u64) -> Timestamp {
+        Timestamp(millis)
+    }
+
+    /// Saturates at the largest timestamp if `secs` is too large to be kept in milliseconds.
+    pub fn from_secs(secs: u64) -> Timestamp {
+        Timestamp(secs.saturating_mul(1000))
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    /// Whole seconds since the epoch, rounding down.
+    pub fn as_secs(&self) -> u64 {
+        self.0 / 1000
+    }
+
     pub fn parse(text: &str) -> Result<Timestamp, ParseIntError> {
         text.parse().map(Timestamp)
     }
 
+    /// Parse the time from either a number or a string holding a number, as some servers
+    /// send it as a string.
     #[cfg(feature = "json_parsing")]
     pub fn from_json(value: &json::JsonValue) -> Result<Timestamp, FromJsonError> {
+        if let Some(text) = value.as_str() {
+            return Ok(Timestamp::parse(text)?);
+        }
+
         value
             .as_u64()
             .map(Timestamp)
             .ok_or(FromJsonError::InvalidField(id::TIME))
     }
+
+    /// Parse a date such as `2021-03-04T05:06:07.890Z` into a timestamp.
+    /// Dates before the epoch become the epoch, as timestamps can't be negative.
+    #[cfg(feature = "chrono")]
+    pub fn from_rfc3339(text: &str) -> Result<Timestamp, chrono::ParseError> {
+        chrono::DateTime::parse_from_rfc3339(text)
+            .map(|date| Timestamp::from(date.with_timezone(&chrono::Utc)))
+    }
+
+    /// Format the timestamp as an RFC3339 date in UTC, keeping the milliseconds.
+    /// Returns `None` if the timestamp is too far in the future for chrono to represent.
+    #[cfg(feature = "chrono")]
+    pub fn to_rfc3339(&self) -> Option<String> {
+        let date = self.to_datetime_utc()?;
+        Some(date.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+    }
+
+    /// The timestamp as a date in UTC.
+    /// Returns `None` if the timestamp is too far in the future for chrono to represent.
+    #[cfg(feature = "chrono")]
+    pub fn to_datetime_utc(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        use chrono::TimeZone;
+        use std::convert::TryFrom;
+        let millis = i64::try_from(self.0).ok()?;
+        chrono::Utc.timestamp_millis_opt(millis).single()
+    }
+}
+/// Dates before the epoch become the epoch, as timestamps can't be negative.
+#[cfg(feature = "chrono")]
+impl From<chrono::DateTime<chrono::Utc>> for Timestamp {
+    fn from(date: chrono::DateTime<chrono::Utc>) -> Timestamp {
+        Timestamp(date.timestamp_millis().max(0) as u64)
+    }
+}
+/// Serialized as a number.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Timestamp {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+/// Accepts either a number or a string holding a number, like `Timestamp::from_json`, as stored
+/// messages may have kept the time in whichever form the server sent it.
+/// Formats that aren't human readable, such as bincode, can't tell which was stored, so they are
+/// only read as a number.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Timestamp {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        struct TimestampVisitor;
+        impl<'de> serde::de::Visitor<'de> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("a timestamp as a number or a string holding a number")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<Timestamp, E> {
+                Ok(Timestamp(value))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<Timestamp, E> {
+                std::convert::TryFrom::try_from(value)
+                    .map(Timestamp)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(value), &self))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Timestamp, E> {
+                Timestamp::parse(value)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Str(value), &self))
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(TimestampVisitor)
+        } else {
+            deserializer.deserialize_u64(TimestampVisitor)
+        }
+    }
 }
 /// An identifier sent by the server that identifies the user.
 pub type UserId = u64;
@@ -89,15 +295,49 @@ pub enum ServerIdentifier<'a> {
     Trip(&'a str),
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Users {
     /// An id that is used to generat new AccessUserId::Generated instances.
     id: UserId,
     /// The id of our own connection.
     pub ourself: Option<AccessUserId>,
     /// Mapping of ids (from server or generated) to info about the user.
+    #[cfg_attr(feature = "serde", serde(with = "users_as_pairs"))]
     pub users: HashMap<AccessUserId, UserInfo>,
 }
+/// Formats like json only allow strings as map keys, so the users are kept as a list of
+/// `(id, info)` pairs instead.
+#[cfg(feature = "serde")]
+mod users_as_pairs {
+    use super::{AccessUserId, HashMap, UserInfo};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        users: &HashMap<AccessUserId, UserInfo>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(users.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<AccessUserId, UserInfo>, D::Error> {
+        Vec::<(AccessUserId, UserInfo)>::deserialize(deserializer)
+            .map(|users| users.into_iter().collect())
+    }
+}
 impl Users {
+    /// Create an empty list of users where we already know the id of our own connection.
+    pub fn with_self(id: AccessUserId) -> Users {
+        Users {
+            ourself: Some(id),
+            ..Users::default()
+        }
+    }
+
+    /// A new id that no other generated id in this `Users` has.
+    /// The number may be the same as a server's user id, but the two never compare equal, so they
+    /// are always kept as separate users.
     pub fn generate_id(&mut self) -> AccessUserId {
         let id = self.id;
         self.id += 1;
@@ -110,6 +350,16 @@ impl Users {
         self.ourself
     }
 
+    /// The color that we have, such as one the server assigned us when we joined.
+    pub fn my_color(&self) -> Option<Color> {
+        self.get(self.ourself?)?.color
+    }
+
+    /// Set the id of our own connection.
+    pub fn set_self(&mut self, id: AccessUserId) {
+        self.ourself = Some(id);
+    }
+
     /// Clear the list of users
     pub fn clear(&mut self) {
         self.users.clear();
@@ -130,6 +380,36 @@ impl Users {
         self.users.insert(id, user_info);
     }
 
+    /// Every user we know of, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (AccessUserId, &UserInfo)> {
+        self.users.iter().map(|(id, info)| (*id, info))
+    }
+
+    /// Every user we know of, in no particular order, with their info mutable.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (AccessUserId, &mut UserInfo)> {
+        self.users.iter_mut().map(|(id, info)| (*id, info))
+    }
+
+    /// The number of users we know of, including those who are offline.
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Remove a user entirely, rather than marking them offline as `apply_online_remove` does.
+    /// Returns their info if they were known.
+    pub fn remove(&mut self, id: AccessUserId) -> Option<UserInfo> {
+        self.users.remove(&id)
+    }
+
+    /// Keep only the users that `f` returns true for, such as to forget users that are offline.
+    pub fn retain<F: FnMut(&AccessUserId, &mut UserInfo) -> bool>(&mut self, f: F) {
+        self.users.retain(f);
+    }
+
     /// Check if the list of users contains the given id.
     pub fn contains_key(&self, id: AccessUserId) -> bool {
         self.users.contains_key(&id)
@@ -143,6 +423,181 @@ impl Users {
             .map(|(id, info)| (*id, info))
     }
 
+    /// Find every online user with the given nickname, ordered by their id.
+    pub fn find_online_nick_all(&self, nick: &str) -> Vec<AccessUserId> {
+        let mut ids: Vec<AccessUserId> = self
+            .users
+            .iter()
+            .filter(|(_, info)| info.online && info.nick == nick)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// The number of online users that we know to be in the channel.
+    pub fn online_count_in_channel(&self, channel: &str) -> usize {
+        self.users
+            .values()
+            .filter(|info| info.online && info.channel.as_deref() == Some(channel))
+            .count()
+    }
+
+    /// Find the user that sent the message and mark them as recently active.
+    /// Returns the id of the author, if they could be found.
+    pub fn apply_chat(&mut self, chat: &server::Chat) -> Option<AccessUserId> {
+        self.apply_chat_events(chat).first().map(UserEvent::id)
+    }
+
+    /// Like `apply_chat`, but describes what changed.
+    pub fn apply_chat_events(&mut self, chat: &server::Chat) -> Vec<UserEvent> {
+        let id = match chat.user_id {
+            Some(user_id) => AccessUserId::Server(user_id),
+            None => match self.find_online_nick(&chat.nick) {
+                Some((id, _)) => id,
+                None => return Vec::new(),
+            },
+        };
+        let info = match self.get_mut(id) {
+            Some(info) => info,
+            None => return Vec::new(),
+        };
+
+        let mut events = Vec::with_capacity(2);
+        if info.nick != chat.nick {
+            let old = std::mem::replace(&mut info.nick, chat.nick.clone());
+            events.push(UserEvent::Renamed {
+                id,
+                old,
+                new: chat.nick.clone(),
+            });
+        }
+        info.last_activity = Some(chat.time);
+        if let MaybeExist::Has(trip) = &chat.trip {
+            info.trip = MaybeExist::Has(trip.clone());
+        }
+        events.push(UserEvent::Updated(id));
+        events
+    }
+
+    /// Add the user that joined, or update them if we already knew of them.
+    /// Users without a server id are matched by their nick, and otherwise get a newly generated
+    /// id. Legacy servers don't allow two users in a channel with the same nick, so a second join
+    /// under a nick that we think is still online is the same user, such as after we missed
+    /// their `onlineRemove`. An online match is preferred over an offline one.
+    /// If the server marked the user with `isme`, they are set as ourself.
+    /// Returns the id of the user.
+    pub fn apply_online_add(&mut self, online_add: &server::OnlineAdd) -> AccessUserId {
+        self.apply_online_add_events(online_add)
+            .first()
+            .map(UserEvent::id)
+            .expect("onlineAdd always produces an event")
+    }
+
+    /// Like `apply_online_add`, but describes what changed.
+    /// The first event is `Added` if the user wasn't known before, and `Updated` otherwise, see
+    /// `UserEvent::Added` for when that happens across reconnects.
+    pub fn apply_online_add_events(&mut self, online_add: &server::OnlineAdd) -> Vec<UserEvent> {
+        let id = match online_add.user_id {
+            Some(user_id) => AccessUserId::Server(user_id),
+            None => self
+                .users
+                .iter()
+                .filter(|(id, info)| {
+                    matches!(id, AccessUserId::Generated(_)) && info.nick == online_add.nick
+                })
+                .min_by_key(|(id, info)| (!info.online, **id))
+                .map(|(id, _)| *id)
+                .unwrap_or_else(|| self.generate_id()),
+        };
+        if online_add.is_me == Some(true) {
+            self.set_self(id);
+        }
+        let info = UserInfo {
+            nick: online_add.nick.clone(),
+            trip: online_add.trip.clone(),
+            online: true,
+            channel: online_add.channel.clone(),
+            level: online_add.level,
+            color: online_add.color,
+            last_activity: Some(online_add.time),
+        };
+
+        match self.users.insert(id, info) {
+            Some(old) if old.nick != online_add.nick => vec![
+                UserEvent::Renamed {
+                    id,
+                    old: old.nick,
+                    new: online_add.nick.clone(),
+                },
+                UserEvent::Updated(id),
+            ],
+            Some(_) => vec![UserEvent::Updated(id)],
+            None => vec![UserEvent::Added(id)],
+        }
+    }
+
+    /// Mark the user that left as offline.
+    /// Without a user id (legacy servers) the user is found by nick, and if several online users
+    /// share it then the one with the lowest id is chosen. Use `find_online_nick_all` beforehand
+    /// if that matters.
+    /// Returns the id of the user, if they could be found.
+    pub fn apply_online_remove(
+        &mut self,
+        online_remove: &server::OnlineRemove,
+    ) -> Option<AccessUserId> {
+        self.apply_online_remove_events(online_remove)
+            .first()
+            .map(UserEvent::id)
+    }
+
+    /// Like `apply_online_remove`, but describes what changed.
+    pub fn apply_online_remove_events(
+        &mut self,
+        online_remove: &server::OnlineRemove,
+    ) -> Vec<UserEvent> {
+        let id = match online_remove.user_id {
+            Some(user_id) => AccessUserId::Server(user_id),
+            None => match self.find_online_nick_all(&online_remove.nick).first() {
+                Some(id) => *id,
+                None => return Vec::new(),
+            },
+        };
+        match self.get_mut(id) {
+            Some(info) => {
+                info.online = false;
+                vec![UserEvent::Removed(id)]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Combine another list of users into this one, such as a fresh list into a cached one.
+    /// Entries from `other` decide the online status and nick, but a known trip or a higher level
+    /// is never replaced by a less informative one.
+    /// Generated ids from `other` are given new ids, as they would otherwise collide with ours.
+    pub fn merge(&mut self, other: Users) {
+        let mut other_ourself = other.ourself;
+        for (id, info) in other.users {
+            let new_id = match id {
+                AccessUserId::Server(_) => id,
+                AccessUserId::Generated(_) => self.generate_id(),
+            };
+            if other_ourself == Some(id) {
+                other_ourself = Some(new_id);
+            }
+
+            match self.users.get_mut(&new_id) {
+                Some(existing) => existing.merge(info),
+                None => self.insert(new_id, info),
+            }
+        }
+
+        if self.ourself.is_none() {
+            self.ourself = other_ourself;
+        }
+    }
+
     pub fn acquire_server_identifier(
         &self,
         id: AccessUserId,
@@ -174,9 +629,49 @@ impl Default for Users {
         }
     }
 }
+/// A change to the list of users from applying a message, so that a UI can update only what
+/// changed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UserEvent {
+    /// A user that we didn't know of was added, so this is the first time we've seen them since
+    /// this `Users` was created.
+    /// Users are kept when they go offline, so one that comes back with the same id is
+    /// `Updated` instead. That includes legacy users without a server id, who are matched by
+    /// nick whether or not we think they are still online. Servers usually give a new user id to
+    /// each connection though, so someone who reconnects to a V2 server is `Added` again under
+    /// their new id.
+    Added(AccessUserId),
+    /// A user went offline.
+    Removed(AccessUserId),
+    /// Some of the info about a user changed.
+    Updated(AccessUserId),
+    /// A user's nick changed.
+    Renamed {
+        id: AccessUserId,
+        old: Nickname,
+        new: Nickname,
+    },
+}
+impl UserEvent {
+    /// The user that the event is about.
+    pub fn id(&self) -> AccessUserId {
+        match self {
+            UserEvent::Added(id)
+            | UserEvent::Removed(id)
+            | UserEvent::Updated(id)
+            | UserEvent::Renamed { id, .. } => *id,
+        }
+    }
+}
+
 /// This exists because not everything might have an id, or we might be connecting to a legacy
 /// HC instance that does not have user ids.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+/// Ordering puts all `Server` ids before all `Generated` ids, and then orders by the id itself.
+/// The two kinds share the same numbers, but `Server(5)` and `Generated(5)` are different users,
+/// so never compare or key by the inner `UserId` alone.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AccessUserId {
     /// Sent from the server
     Server(UserId),
@@ -190,11 +685,556 @@ impl AccessUserId {
             AccessUserId::Generated(_) => None,
         }
     }
+
+    /// A short string that keeps which kind of id it is, such as `s123` or `g4`, for use as a
+    /// storage key. `from_key` turns it back into the id.
+    pub fn to_key(&self) -> String {
+        match self {
+            AccessUserId::Server(id) => format!("s{}", id),
+            AccessUserId::Generated(id) => format!("g{}", id),
+        }
+    }
+
+    /// Parse a key made by `to_key`.
+    pub fn from_key(key: &str) -> Option<AccessUserId> {
+        let (kind, id) = (key.get(..1)?, key.get(1..)?);
+        if !id.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        let id = id.parse().ok()?;
+        match kind {
+            "s" => Some(AccessUserId::Server(id)),
+            "g" => Some(AccessUserId::Generated(id)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UserInfo {
     pub nick: Nickname,
     pub trip: MaybeExist<Trip>,
     pub online: bool,
+    /// The channel the user is in. Only V2 tells us this, as it supports being in multiple
+    /// channels at once.
+    pub channel: Option<Channel>,
+    /// The user's permission level, if the server told us it.
+    pub level: Option<UserLevel>,
+    /// The color that the user has, which the server may have assigned them.
+    pub color: Option<Color>,
+    /// The last time that we saw the user do something.
+    /// This is only updated on the events that we observe (joining, chatting), so it is not
+    /// a reliable measure of whether they are actually away.
+    pub last_activity: Option<Timestamp>,
+}
+impl UserInfo {
+    /// Update this with newer info about the same user, without losing anything we knew.
+    fn merge(&mut self, other: UserInfo) {
+        self.nick = other.nick;
+        self.online = other.online;
+        if other.channel.is_some() {
+            self.channel = other.channel;
+        }
+        match (&self.trip, other.trip) {
+            (_, MaybeExist::Has(trip)) => self.trip = MaybeExist::Has(trip),
+            (MaybeExist::Unknown, MaybeExist::Not) => self.trip = MaybeExist::Not,
+            _ => {}
+        }
+        self.level = self.level.max(other.level);
+        if other.color.is_some() {
+            self.color = other.color;
+        }
+        self.last_activity = self.last_activity.max(other.last_activity);
+    }
+
+    /// How long it has been since the user's last observed activity.
+    /// Returns `None` if we've never seen them do anything, or if `now` is before that.
+    /// The server sends times in milliseconds, so `now` is expected to be as well.
+    pub fn idle_for(&self, now: Timestamp) -> Option<Duration> {
+        let last = self.last_activity?;
+        now.0.checked_sub(last.0).map(Duration::from_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn online_add(nick: &str, user_id: Option<UserId>, time: u64) -> server::OnlineAdd {
+        server::OnlineAdd {
+            channel: Some("lounge".to_owned()),
+            color: None,
+            hash: None,
+            is_bot: None,
+            is_me: None,
+            level: None,
+            nick: nick.to_owned(),
+            time: Timestamp(time),
+            trip: MaybeExist::Not,
+            user_type: None,
+            user_id,
+        }
+    }
+
+    fn chat(nick: &str, user_id: Option<UserId>, time: u64) -> server::Chat {
+        server::Chat {
+            nick: nick.to_owned(),
+            user_type: None,
+            user_id,
+            channel: Some("lounge".to_owned()),
+            text: "hi".to_owned(),
+            level: None,
+            is_mod: false,
+            is_admin: false,
+            trip: MaybeExist::Not,
+            time: Timestamp(time),
+            id: None,
+            broadcast_channels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn apply_chat_advances_last_activity() {
+        let mut users = Users::default();
+        let id = users.apply_online_add(&online_add("alice", Some(1), 100));
+        assert_eq!(users.get(id).unwrap().last_activity, Some(Timestamp(100)));
+
+        users.apply_chat(&chat("alice", Some(1), 200));
+        assert_eq!(users.get(id).unwrap().last_activity, Some(Timestamp(200)));
+        users.apply_chat(&chat("alice", Some(1), 350));
+        assert_eq!(users.get(id).unwrap().last_activity, Some(Timestamp(350)));
+
+        let info = users.get(id).unwrap();
+        assert_eq!(info.idle_for(Timestamp(1350)), Some(Duration::from_secs(1)));
+        assert_eq!(info.idle_for(Timestamp(300)), None);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn rfc3339_round_trip() {
+        let time = Timestamp::from_rfc3339("2021-03-04T05:06:07.890Z").unwrap();
+        assert_eq!(time, Timestamp(1614834367890));
+        assert_eq!(
+            time.to_rfc3339().as_deref(),
+            Some("2021-03-04T05:06:07.890Z")
+        );
+
+        assert_eq!(
+            Timestamp::from_rfc3339("2021-03-04T07:06:07.890+02:00").unwrap(),
+            time
+        );
+        assert_eq!(
+            Timestamp::from_rfc3339("1969-12-31T23:59:59Z").unwrap(),
+            Timestamp(0)
+        );
+        assert!(Timestamp::from_rfc3339("yesterday").is_err());
+        assert_eq!(Timestamp(u64::MAX).to_rfc3339(), None);
+    }
+
+    #[test]
+    fn merge_overlapping_rosters() {
+        let mut cached = Users::with_self(AccessUserId::Server(1));
+        cached.apply_online_add(&server::OnlineAdd {
+            trip: MaybeExist::Has(Trip("abc".to_owned())),
+            level: Some(1000),
+            ..online_add("alice", Some(1), 100)
+        });
+        cached.apply_online_add(&online_add("bob", Some(2), 100));
+
+        let mut fresh = Users::with_self(AccessUserId::Server(9));
+        fresh.apply_online_add(&online_add("alice", Some(1), 200));
+        fresh.apply_online_add(&online_add("carol", Some(3), 200));
+        fresh.apply_online_remove(&server::OnlineRemove {
+            nick: "alice".to_owned(),
+            user_id: Some(1),
+            time: Timestamp(300),
+            channel: None,
+        });
+
+        cached.merge(fresh);
+        assert_eq!(cached.len(), 3);
+        assert_eq!(cached.ourself(), Some(AccessUserId::Server(1)));
+
+        let alice = cached.get(AccessUserId::Server(1)).unwrap();
+        assert!(!alice.online);
+        assert_eq!(alice.trip, MaybeExist::Has(Trip("abc".to_owned())));
+        assert_eq!(alice.level, Some(1000));
+        assert!(cached.get(AccessUserId::Server(2)).unwrap().online);
+        assert!(cached.get(AccessUserId::Server(3)).unwrap().online);
+    }
+
+    #[test]
+    fn access_user_id_ordering() {
+        let mut ids = vec![
+            AccessUserId::Generated(1),
+            AccessUserId::Server(7),
+            AccessUserId::Generated(0),
+            AccessUserId::Server(2),
+        ];
+        ids.sort();
+        assert_eq!(
+            ids,
+            vec![
+                AccessUserId::Server(2),
+                AccessUserId::Server(7),
+                AccessUserId::Generated(0),
+                AccessUserId::Generated(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn preset_ourself() {
+        let mut users = Users::with_self(AccessUserId::Server(4));
+        assert_eq!(users.ourself(), Some(AccessUserId::Server(4)));
+        assert_eq!(Users::default().ourself(), None);
+
+        users.set_self(AccessUserId::Generated(0));
+        assert_eq!(users.ourself(), Some(AccessUserId::Generated(0)));
+    }
+
+    #[test]
+    fn trip_compares_to_str() {
+        let trip = Trip("abc123".to_owned());
+        assert!(trip == "abc123");
+        assert!(trip != "abc124");
+        assert!(trip == *"abc123");
+
+        // `Hash` is a `String`, which already compares against `&str`.
+        let hash: Hash = "xyz".to_owned();
+        assert!(hash == "xyz");
+    }
+
+    fn user_info(nick: &str) -> UserInfo {
+        UserInfo {
+            nick: nick.to_owned(),
+            trip: MaybeExist::Unknown,
+            online: true,
+            channel: None,
+            level: None,
+            color: None,
+            last_activity: None,
+        }
+    }
+
+    #[test]
+    fn online_remove_with_duplicate_nicks() {
+        let mut users = Users::default();
+        let first = users.generate_id();
+        let second = users.generate_id();
+        users.insert(second, user_info("alice"));
+        users.insert(first, user_info("alice"));
+        assert_eq!(users.find_online_nick_all("alice"), vec![first, second]);
+
+        let removed = users.apply_online_remove(&server::OnlineRemove {
+            channel: None,
+            nick: "alice".to_owned(),
+            time: Timestamp(0),
+            user_id: None,
+        });
+        assert_eq!(removed, Some(first));
+        assert!(!users.get(first).unwrap().online);
+        assert_eq!(users.find_online_nick_all("alice"), vec![second]);
+    }
+
+    #[test]
+    fn password_style_per_api() {
+        assert_eq!(
+            ServerApi::HackChatV2.password_style(),
+            PasswordStyle::SeparateField
+        );
+        assert_eq!(
+            ServerApi::HackChatPreV2.password_style(),
+            PasswordStyle::SeparateField
+        );
+        assert_eq!(
+            ServerApi::HackChatLegacy.password_style(),
+            PasswordStyle::NickSuffix
+        );
+    }
+
+    #[test]
+    fn trip_tristate_round_trip() {
+        let states = [
+            (MaybeExist::Has(Trip("abc123".to_owned())), "abc123"),
+            (MaybeExist::Not, ""),
+            (MaybeExist::Unknown, "?"),
+        ];
+        for (state, text) in states.iter() {
+            assert_eq!(state.to_tristate(), *text);
+            assert_eq!(MaybeExist::<Trip>::from_tristate(text), *state);
+        }
+    }
+
+    #[test]
+    fn online_count_per_channel() {
+        let mut users = Users::default();
+        users.apply_online_add(&online_add("alice", Some(1), 0));
+        users.apply_online_add(&online_add("bob", Some(2), 0));
+        users.apply_online_add(&server::OnlineAdd {
+            channel: Some("programming".to_owned()),
+            ..online_add("carol", Some(3), 0)
+        });
+        users.apply_online_remove(&server::OnlineRemove {
+            channel: None,
+            nick: "bob".to_owned(),
+            time: Timestamp(1),
+            user_id: Some(2),
+        });
+
+        assert_eq!(users.online_count_in_channel("lounge"), 1);
+        assert_eq!(users.online_count_in_channel("programming"), 1);
+        assert_eq!(users.online_count_in_channel("elsewhere"), 0);
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn timestamp_from_json() {
+        assert_eq!(
+            Timestamp::from_json(&json::JsonValue::from(5)),
+            Ok(Timestamp(5))
+        );
+        assert_eq!(
+            Timestamp::from_json(&json::JsonValue::from("5")),
+            Ok(Timestamp(5))
+        );
+        assert_eq!(
+            Timestamp::from_json(&json::JsonValue::from("soon")),
+            Err(FromJsonError::InvalidNumber(id::TIME))
+        );
+        assert_eq!(
+            Timestamp::from_json(&json::JsonValue::Null),
+            Err(FromJsonError::InvalidField(id::TIME))
+        );
+    }
+
+    #[test]
+    fn apply_events() {
+        let mut users = Users::default();
+        let id = AccessUserId::Server(1);
+        assert_eq!(
+            users.apply_online_add_events(&online_add("alice", Some(1), 0)),
+            [UserEvent::Added(id)]
+        );
+        assert_eq!(
+            users.apply_chat_events(&chat("alicia", Some(1), 1)),
+            [
+                UserEvent::Renamed {
+                    id,
+                    old: "alice".to_owned(),
+                    new: "alicia".to_owned(),
+                },
+                UserEvent::Updated(id),
+            ]
+        );
+        assert_eq!(
+            users.apply_online_remove_events(&server::OnlineRemove {
+                channel: None,
+                nick: "alicia".to_owned(),
+                time: Timestamp(2),
+                user_id: Some(1),
+            }),
+            [UserEvent::Removed(id)]
+        );
+        assert_eq!(
+            users.apply_chat_events(&chat("nobody", None, 3)),
+            Vec::<UserEvent>::new()
+        );
+    }
+
+    #[test]
+    fn server_api_as_key() {
+        let apis = [
+            ServerApi::HackChatV2,
+            ServerApi::HackChatPreV2,
+            ServerApi::HackChatLegacy,
+        ];
+        let endpoints: HashMap<ServerApi, &str> = apis
+            .iter()
+            .map(|api| (*api, api.default_endpoint()))
+            .collect();
+        assert_eq!(endpoints.len(), 3);
+        assert!(apis.iter().all(|api| endpoints.contains_key(api)));
+    }
+
+    #[test]
+    fn limits_per_api() {
+        for server_api in [
+            ServerApi::HackChatV2,
+            ServerApi::HackChatPreV2,
+            ServerApi::HackChatLegacy,
+        ] {
+            let limits = server_api.limits();
+            assert_eq!(limits.max_text, MAX_TEXT_LENGTH);
+            assert_eq!(limits.max_nick, MAX_NICK_LENGTH);
+            assert_eq!(limits.max_channel, MAX_CHANNEL_LENGTH);
+        }
+    }
+
+    #[test]
+    fn server_and_generated_ids_are_distinct() {
+        assert_ne!(AccessUserId::Server(5), AccessUserId::Generated(5));
+
+        let mut users = Users::default();
+        users.insert(AccessUserId::Server(5), user_info("alice"));
+        users.insert(AccessUserId::Generated(5), user_info("bob"));
+        assert_eq!(users.len(), 2);
+        assert_eq!(users.get(AccessUserId::Server(5)).unwrap().nick, "alice");
+        assert_eq!(users.get(AccessUserId::Generated(5)).unwrap().nick, "bob");
+    }
+
+    #[test]
+    fn online_add_with_isme_sets_self() {
+        let color = Some(Color::rgb(255, 0, 16));
+        let mut users = Users::default();
+        users.apply_online_add(&online_add("bob", Some(2), 0));
+        assert_eq!(users.ourself(), None);
+        assert_eq!(users.my_color(), None);
+
+        let id = users.apply_online_add(&server::OnlineAdd {
+            is_me: Some(true),
+            color,
+            ..online_add("alice", Some(1), 0)
+        });
+        assert_eq!(id, AccessUserId::Server(1));
+        assert_eq!(users.ourself(), Some(id));
+        assert_eq!(users.my_color(), color);
+
+        users.apply_online_add(&server::OnlineAdd {
+            is_me: Some(false),
+            ..online_add("carol", Some(3), 0)
+        });
+        assert_eq!(users.ourself(), Some(id));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn online_add_parses_isme() {
+        use crate::util::FromJson;
+
+        let online_add = server::OnlineAdd::from_json(
+            json::object! { cmd: "onlineAdd", nick: "alice", isme: true, color: "ff0010", time: 0 },
+            ServerApi::HackChatV2,
+        )
+        .unwrap();
+        assert_eq!(online_add.is_me, Some(true));
+
+        let mut users = Users::default();
+        let id = users.apply_online_add(&online_add);
+        assert_eq!(users.ourself(), Some(id));
+        assert_eq!(users.my_color(), Some(Color::rgb(255, 0, 16)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn timestamp_deserializes_number_or_string() {
+        assert_eq!(
+            serde_json::from_str::<Timestamp>("1680000000").unwrap(),
+            Timestamp(1680000000)
+        );
+        assert_eq!(
+            serde_json::from_str::<Timestamp>("\"1680000000\"").unwrap(),
+            Timestamp(1680000000)
+        );
+        assert!(serde_json::from_str::<Timestamp>("\"soon\"").is_err());
+        assert!(serde_json::from_str::<Timestamp>("-1").is_err());
+    }
+
+    #[test]
+    fn timestamp_unit_conversions() {
+        assert_eq!(Timestamp::from_millis(1500), Timestamp(1500));
+        assert_eq!(Timestamp::from_secs(2), Timestamp(2000));
+        assert_eq!(Timestamp::from_secs(u64::MAX), Timestamp(u64::MAX));
+        assert_eq!(Timestamp(1999).as_millis(), 1999);
+        assert_eq!(Timestamp(1999).as_secs(), 1);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn datetime_utc_is_checked() {
+        use chrono::TimeZone;
+
+        assert_eq!(
+            Timestamp(1680000000000).to_datetime_utc(),
+            chrono::Utc.timestamp_millis_opt(1680000000000).single()
+        );
+        assert_eq!(Timestamp(10u64.pow(16)).to_datetime_utc(), None);
+        assert_eq!(Timestamp(u64::MAX).to_datetime_utc(), None);
+    }
+
+    #[test]
+    fn access_user_id_key_round_trip() {
+        for &id in &[AccessUserId::Server(123), AccessUserId::Generated(4)] {
+            assert_eq!(AccessUserId::from_key(&id.to_key()), Some(id));
+        }
+        assert_eq!(AccessUserId::Server(123).to_key(), "s123");
+        assert_eq!(AccessUserId::Generated(4).to_key(), "g4");
+        assert_eq!(AccessUserId::from_key("x1"), None);
+        assert_eq!(AccessUserId::from_key("s"), None);
+        assert_eq!(AccessUserId::from_key(""), None);
+    }
+
+    #[test]
+    fn repeated_online_add_is_added_then_updated() {
+        let mut users = Users::default();
+        let id = AccessUserId::Server(1);
+        assert_eq!(
+            users.apply_online_add_events(&online_add("alice", Some(1), 0)),
+            [UserEvent::Added(id)]
+        );
+        assert_eq!(
+            users.apply_online_add_events(&online_add("alice", Some(1), 1)),
+            [UserEvent::Updated(id)]
+        );
+
+        // Legacy users are matched by nick, even when we still think they are online.
+        let legacy = match users.apply_online_add_events(&online_add("bob", None, 0))[..] {
+            [UserEvent::Added(id)] => id,
+            ref events => panic!("{:?}", events),
+        };
+        assert_eq!(
+            users.apply_online_add_events(&online_add("bob", None, 1)),
+            [UserEvent::Updated(legacy)]
+        );
+        assert_eq!(users.find_online_nick_all("bob"), [legacy]);
+
+        users.apply_online_remove(&server::OnlineRemove {
+            channel: None,
+            nick: "bob".to_owned(),
+            time: Timestamp(2),
+            user_id: None,
+        });
+        assert_eq!(
+            users.apply_online_add_events(&online_add("bob", None, 3)),
+            [UserEvent::Updated(legacy)]
+        );
+    }
+
+    #[test]
+    fn remove_and_retain_users() {
+        let mut users = Users::default();
+        users.insert(AccessUserId::Server(1), user_info("alice"));
+        users.insert(AccessUserId::Server(2), user_info("bob"));
+
+        assert_eq!(
+            users.remove(AccessUserId::Server(1)),
+            Some(user_info("alice"))
+        );
+        assert!(!users.contains_key(AccessUserId::Server(1)));
+        assert_eq!(users.remove(AccessUserId::Server(1)), None);
+        assert_eq!(users.remove(AccessUserId::Generated(2)), None);
+        assert!(users.contains_key(AccessUserId::Server(2)));
+
+        users.insert(
+            AccessUserId::Server(3),
+            UserInfo {
+                online: false,
+                ..user_info("carol")
+            },
+        );
+        users.retain(|_, info| info.online);
+        assert!(users.contains_key(AccessUserId::Server(2)));
+        assert!(!users.contains_key(AccessUserId::Server(3)));
+    }
 }
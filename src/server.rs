@@ -1,17 +1,26 @@
-use std::{collections::HashMap, convert::TryFrom};
+use std::{convert::TryFrom, time::Duration};
 
 #[cfg(feature = "json_parsing")]
-use crate::util::{as_array, as_object, FromJson, FromJsonError, IntoJson};
+use crate::util::{as_array, as_channel_list, FromJson, FromJsonError, IntoJson};
 #[cfg(feature = "json_parsing")]
-use json::JsonValue;
+use json::{object, JsonValue};
 
+#[cfg(feature = "json_parsing")]
+use crate::{id, ServerApi};
 use crate::{
-    id, util::Color, util::Command, util::MaybeExist, util::ServerCommand, Channel, Hash, Nickname,
-    ServerApi, SessionId, Text, Timestamp, Trip, UserId, UserLevel,
+    normalize_channel, util::Authored, util::Color, util::Command, util::MaybeExist,
+    util::ServerCommand, util::TimedEvent, AccessUserId, Channel, Hash, Nickname, SessionId, Text,
+    Timestamp, Trip, UserId, UserLevel, Users,
 };
 
+/// The V2 level of admins.
+pub const ADMIN_LEVEL: UserLevel = 9999999;
+/// The V2 level of moderators.
+pub const MOD_LEVEL: UserLevel = 999999;
+
 /// The type of the user. Deprecated in v2 and replaced with levels.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UserType {
     // "userType": "user"
     User,
@@ -21,6 +30,32 @@ pub enum UserType {
     Admin,
 }
 impl UserType {
+    /// The user type that a V2 permission level corresponds to.
+    pub fn from_level(level: UserLevel) -> UserType {
+        if level >= ADMIN_LEVEL {
+            UserType::Admin
+        } else if level >= MOD_LEVEL {
+            UserType::Mod
+        } else {
+            UserType::User
+        }
+    }
+
+    /// Decide the user type from everything a message might tell us about it. The V2 level wins,
+    /// as that is what the server actually uses, then the explicit user type.
+    fn effective(level: Option<UserLevel>, user_type: Option<UserType>) -> Option<UserType> {
+        level.map(UserType::from_level).or(user_type)
+    }
+
+    /// The name of the user type, as the server sends it in `userType`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserType::User => "user",
+            UserType::Mod => "mod",
+            UserType::Admin => "admin",
+        }
+    }
+
     // TODO: should this use a MaybeExist?
     #[cfg(feature = "json_parsing")]
     pub fn from_json(value: &JsonValue) -> Option<UserType> {
@@ -42,8 +77,18 @@ impl TryFrom<&str> for UserType {
     }
 }
 
+/// Write the trip into `value`, if there is one.
+/// Both `Unknown` and `Not` leave the field out, so a `Not` trip is parsed back as `Unknown`.
+#[cfg(feature = "json_parsing")]
+fn set_trip(value: &mut JsonValue, trip: MaybeExist<Trip>) {
+    if let MaybeExist::Has(trip) = trip {
+        value[id::TRIP] = trip.0.into();
+    }
+}
+
 /// Informs client about the users within a channel.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct OnlineSet {
     /// The nicknames of all the users in the channel. Legacy/PreV2/V2
     pub nicks: Option<Vec<Nickname>>,
@@ -54,47 +99,106 @@ pub struct OnlineSet {
     /// The time that we joined.
     pub time: Timestamp,
 }
+impl OnlineSet {
+    /// Our own entry in the users, which the server marks with `isme`.
+    pub fn me(&self) -> Option<&OnlineSetUser> {
+        self.users
+            .as_ref()?
+            .iter()
+            .find(|user| user.is_me == Some(true))
+    }
+
+    /// Whether `nicks` and `users` agree on how many users there are. A mismatch means the
+    /// message was malformed. If only one of them was sent there is nothing to compare.
+    pub fn is_consistent(&self) -> bool {
+        match (&self.nicks, &self.users) {
+            (Some(nicks), Some(users)) => nicks.len() == users.len(),
+            _ => true,
+        }
+    }
+}
 impl Command for OnlineSet {
     const CMD: &'static str = "onlineSet";
 }
 impl ServerCommand for OnlineSet {}
+impl TimedEvent for OnlineSet {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for OnlineSet {
+    fn into_json(self, server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        if let Some(nicks) = self.nicks {
+            value["nicks"] = nicks.into();
+        }
+        if let Some(users) = self.users {
+            value["users"] = users
+                .into_iter()
+                .map(|user| user.into_json(server_api))
+                .collect::<Vec<JsonValue>>()
+                .into();
+        }
+        if let Some(channel) = self.channel {
+            // Servers send the channel as `channel`, but it is parsed from `text`, so both are
+            // written.
+            value[id::CHANNEL] = channel.clone().into();
+            value[id::TEXT] = channel.into();
+        }
+        value[id::TIME] = self.time.0.into();
+        value
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl FromJson for OnlineSet {
-    fn from_json(mut json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
         if json[id::CMD].as_str() != Some(Self::CMD) {
             return Err(FromJsonError::InvalidCommandField(Self::CMD));
         }
 
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(mut json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
         const NICKS: &str = "nicks";
         const USERS: &str = "users";
 
-        let nicks = as_array(json[NICKS].take())
-            .map(|x| {
-                x.into_iter()
-                    .map(|mut x| x.take_string().map(Nickname::from))
-                    .collect::<Option<Vec<Nickname>>>()
-            })
-            .flatten();
+        let nicks = as_array(json[NICKS].take()).and_then(|x| {
+            x.into_iter()
+                .map(|mut x| x.take_string())
+                .collect::<Option<Vec<Nickname>>>()
+        });
+        let channel = json[id::TEXT].take_string();
+        // Some server versions send just the nicks in `users`, so we accept those as users that
+        // we only know the nick of.
         let users = as_array(json[USERS].take())
             .map(|users| {
                 users
                     .into_iter()
-                    .map(|x| OnlineSetUser::from_json(x, server_api))
+                    .map(|mut x| match x.take_string() {
+                        Some(nick) => Ok(OnlineSetUser::from_nick(
+                            nick,
+                            channel.clone().unwrap_or_default(),
+                        )),
+                        None => OnlineSetUser::from_json_nested(x, server_api),
+                    })
                     .collect::<Result<Vec<OnlineSetUser>, FromJsonError>>()
             })
             .transpose()?;
-        let channel = json[id::TEXT].take_string();
         let time = Timestamp::from_json(&json[id::TIME])?;
         Ok(Self {
             nicks,
             users,
-            channel: channel.map(Channel::from),
+            channel,
             time,
         })
     }
 }
 /// Detailed information about a specific user from OnlineSet
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct OnlineSetUser {
     /// The channel they are in. Unsure as to why this is bothered to be included.
     pub channel: Channel,
@@ -117,6 +221,59 @@ pub struct OnlineSetUser {
     /// The user's permission level.
     pub level: Option<UserLevel>,
 }
+impl OnlineSetUser {
+    /// The user type, preferring what their level says over their `user_type`.
+    /// This is the same as `Chat::effective_user_type`, so a user is shown consistently.
+    pub fn effective_user_type(&self) -> UserType {
+        UserType::effective(self.level, self.user_type).unwrap_or(UserType::User)
+    }
+
+    /// Create a user where all we know is their nick and the channel they're in.
+    /// The hash is left empty, since we were not told it.
+    pub fn from_nick(nick: Nickname, channel: Channel) -> OnlineSetUser {
+        OnlineSetUser {
+            channel,
+            is_me: None,
+            is_bot: None,
+            nick,
+            trip: MaybeExist::Unknown,
+            user_type: None,
+            user_id: None,
+            hash: Hash::new(),
+            color: None,
+            level: None,
+        }
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for OnlineSetUser {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CHANNEL] = self.channel.into();
+        if let Some(is_me) = self.is_me {
+            value["isme"] = is_me.into();
+        }
+        if let Some(is_bot) = self.is_bot {
+            value[id::IS_BOT] = is_bot.into();
+        }
+        value[id::NICK] = self.nick.into();
+        set_trip(&mut value, self.trip);
+        if let Some(user_type) = self.user_type {
+            value[id::USER_TYPE] = user_type.as_str().into();
+        }
+        if let Some(user_id) = self.user_id {
+            value[id::USER_ID] = user_id.into();
+        }
+        value[id::HASH] = self.hash.into();
+        if let Some(color) = self.color {
+            value[id::COLOR] = color.to_hex_with_alpha().into();
+        }
+        if let Some(level) = self.level {
+            value[id::LEVEL] = level.into();
+        }
+        value
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl FromJson for OnlineSetUser {
     fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
@@ -157,15 +314,25 @@ impl FromJson for OnlineSetUser {
     }
 }
 
+/// The public channels and their user counts.
+/// With the `indexmap` feature this keeps the order that the server sent them in.
+#[cfg(feature = "indexmap")]
+pub type PublicChannels = indexmap::IndexMap<Channel, u32>;
+/// The public channels and their user counts.
+/// With the `indexmap` feature this keeps the order that the server sent them in.
+#[cfg(not(feature = "indexmap"))]
+pub type PublicChannels = std::collections::HashMap<Channel, u32>;
+
 /// Information about the user's session and the server.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Session {
     /// Number of users server wide
     pub users: u32,
     /// Number of channels with at least a single user server-wide.
     pub channels: u32,
     /// A list of certain 'public' (frontpaged) channels with user count.
-    pub public: HashMap<Channel, u32>,
+    pub public: PublicChannels,
     /// The user's session id.
     pub session_id: SessionId,
     /// Whether or not their session was restored.
@@ -173,17 +340,76 @@ pub struct Session {
     /// The time that this was sent at.
     pub time: Timestamp,
 }
+impl Session {
+    /// The session id, but only if the server restored the session we asked to resume.
+    /// When this is `Some`, it is the id to use from now on.
+    pub fn resumed_id(&self) -> Option<&SessionId> {
+        if self.restored == Some(true) {
+            Some(&self.session_id)
+        } else {
+            None
+        }
+    }
+
+    /// The public channels whose names start with `prefix`, ignoring case, with the most
+    /// populated first. Channels with the same count are ordered by name.
+    pub fn public_starting_with(&self, prefix: &str) -> Vec<(&Channel, u32)> {
+        let prefix = prefix.to_lowercase();
+        let mut channels: Vec<(&Channel, u32)> = self
+            .public
+            .iter()
+            .filter(|(channel, _)| channel.to_lowercase().starts_with(&prefix))
+            .map(|(channel, user_count)| (channel, *user_count))
+            .collect();
+        channels.sort_by(|(a_channel, a_count), (b_channel, b_count)| {
+            b_count.cmp(a_count).then_with(|| a_channel.cmp(b_channel))
+        });
+        channels
+    }
+}
 impl Command for Session {
     const CMD: &'static str = "session";
 }
 impl ServerCommand for Session {}
+impl TimedEvent for Session {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Session {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value["users"] = self.users.into();
+        value["chans"] = self.channels.into();
+        let mut public = JsonValue::new_object();
+        for (channel, user_count) in self.public {
+            public[channel.as_str()] = user_count.into();
+        }
+        value["public"] = public;
+        value["sessionID"] = self.session_id.into();
+        if let Some(restored) = self.restored {
+            value["restored"] = restored.into();
+        }
+        value[id::TIME] = self.time.0.into();
+        value
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl FromJson for Session {
-    fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
         if json[id::CMD].as_str() != Some(Self::CMD) {
             return Err(FromJsonError::InvalidCommandField(Self::CMD));
         }
 
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
         const USERS: &str = "users";
         const CHANNELS: &str = "chans";
         const PUBLIC: &str = "public";
@@ -196,21 +422,21 @@ impl FromJson for Session {
         let channels = json[CHANNELS]
             .as_u32()
             .ok_or(FromJsonError::InvalidField(CHANNELS))?;
-        let public = as_object(json[PUBLIC].take())
-            .map(|mut object| {
-                // TODO: it would be nice to take ownership of key if possible.
-                let mut public = HashMap::with_capacity(object.len());
-                for (channel, user_count) in object.iter_mut() {
-                    let channel = channel.to_owned();
+        // The json crate keeps object keys in its own representation and only hands out `&str`,
+        // so there is no way to take ownership of them; each channel name is copied once.
+        let public = match &json[PUBLIC] {
+            JsonValue::Object(object) => {
+                let mut public = PublicChannels::with_capacity(object.len());
+                for (channel, user_count) in object.iter() {
                     let user_count = user_count
                         .as_u32()
                         .ok_or(FromJsonError::InvalidField(PUBLIC))?;
-                    public.insert(channel, user_count);
+                    public.insert(channel.to_owned(), user_count);
                 }
-                Ok(public)
-            })
-            .transpose()?
-            .unwrap_or_else(HashMap::new);
+                public
+            }
+            _ => PublicChannels::default(),
+        };
         let session_id = json[SESSION_ID]
             .take_string()
             .ok_or(FromJsonError::InvalidField(SESSION_ID))?;
@@ -231,22 +457,62 @@ impl FromJson for Session {
 /// In the legacy server this often has to be synthesized (see the synthetic module)
 /// into types which let you deal with them.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Info {
     pub text: Text,
     pub channel: Option<Channel>,
     pub time: Timestamp,
 }
+impl Info {
+    /// Channels that the text refers to with the site's `?channel` form, such as in invites.
+    /// Punctuation directly after the name is not counted as part of it.
+    pub fn mentioned_channels(&self) -> Vec<&str> {
+        self.text
+            .split_whitespace()
+            .filter(|token| token.starts_with('?'))
+            .map(|token| {
+                normalize_channel(token).trim_end_matches(|c: char| c.is_ascii_punctuation())
+            })
+            .filter(|channel| !channel.is_empty())
+            .collect()
+    }
+}
 impl Command for Info {
     const CMD: &'static str = "info";
 }
 impl ServerCommand for Info {}
+impl TimedEvent for Info {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Info {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::TEXT] = self.text.into();
+        if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        value[id::TIME] = self.time.0.into();
+        value
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl FromJson for Info {
-    fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
         if json[id::CMD].as_str() != Some(Self::CMD) {
             return Err(FromJsonError::InvalidCommandField(Self::CMD));
         }
 
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
         let text = json[id::TEXT]
             .take_string()
             .ok_or(FromJsonError::InvalidField(id::TEXT))?;
@@ -264,6 +530,7 @@ impl FromJson for Info {
 // latest permissions state?
 /// A chat message from a user on the server
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Chat {
     /// Nickname of the user
     pub nick: Nickname,
@@ -288,21 +555,200 @@ pub struct Chat {
     pub trip: MaybeExist<Trip>,
     /// The time the message was sent.
     pub time: Timestamp,
+    /// Identifies the message, for replying to or editing it. Only sent by V2, so this is always
+    /// `None` on Legacy/PreV2.
+    pub id: Option<u64>,
+    /// Every channel the message was broadcast to, when the server sent `channel` as an array.
+    /// `channel` is then the first of them. Empty when `channel` was a single string.
+    pub broadcast_channels: Vec<Channel>,
+}
+impl Chat {
+    /// The user type of the sender, preferring what their level says, then their `user_type`,
+    /// and then the legacy `admin`/`mod` flags.
+    pub fn effective_user_type(&self) -> UserType {
+        UserType::effective(self.level, self.user_type).unwrap_or(if self.is_admin {
+            UserType::Admin
+        } else if self.is_mod {
+            UserType::Mod
+        } else {
+            UserType::User
+        })
+    }
+
+    /// The id of the message, if the server gave it one.
+    pub fn message_id(&self) -> Option<u64> {
+        self.id
+    }
+
+    /// The nicks that the text mentions with `@nick`.
+    /// Characters that can't be in a nick, such as punctuation, end the mention.
+    pub fn mentions(&self) -> Vec<&str> {
+        self.text
+            .split_whitespace()
+            .filter_map(|token| token.strip_prefix('@'))
+            .map(|token| {
+                let end = token
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(token.len());
+                &token[..end]
+            })
+            .filter(|nick| !nick.is_empty())
+            .collect()
+    }
+
+    /// Whether the text mentions our own nick, ignoring case.
+    pub fn mentions_self(&self, users: &Users) -> bool {
+        let nick = match users.ourself().and_then(|id| users.get(id)) {
+            Some(info) => &info.nick,
+            None => return false,
+        };
+        self.mentions()
+            .iter()
+            .any(|mention| mention.eq_ignore_ascii_case(nick))
+    }
+
+    /// The channels that the message was sent in, whether the server gave one or several.
+    pub fn channels(&self) -> Vec<&Channel> {
+        if self.broadcast_channels.is_empty() {
+            self.channel.iter().collect()
+        } else {
+            self.broadcast_channels.iter().collect()
+        }
+    }
+
+    /// Some servers deliver whispers as a chat message from the sender, with text of the form
+    /// `whispered: <text>`. Returns `None` for ordinary messages.
+    pub fn as_whisper(&self) -> Option<synthetic::Whisper> {
+        let text = self.text.strip_prefix("whispered: ")?;
+        Some(synthetic::Whisper {
+            from: self.nick.clone(),
+            text: text.to_owned(),
+            time: self.time,
+        })
+    }
+
+    /// The text with any html entities that the server escaped it with decoded, such as `&lt;`.
+    /// The stored `text` is left as the server sent it.
+    pub fn decoded_text(&self) -> String {
+        crate::util::decode_entities(&self.text)
+    }
 }
 impl Command for Chat {
     const CMD: &'static str = "chat";
 }
 impl ServerCommand for Chat {}
+impl TimedEvent for Chat {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+impl Authored for Chat {
+    fn author_nick(&self) -> Option<&str> {
+        Some(&self.nick)
+    }
+
+    fn author_trip(&self) -> MaybeExist<&Trip> {
+        self.trip.as_ref()
+    }
+
+    fn author_id(&self) -> Option<UserId> {
+        self.user_id
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Chat {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::NICK] = self.nick.into();
+        if let Some(user_type) = self.user_type {
+            value[id::USER_TYPE] = user_type.as_str().into();
+        }
+        if let Some(user_id) = self.user_id {
+            value[id::USER_ID] = user_id.into();
+        }
+        if !self.broadcast_channels.is_empty() {
+            value[id::CHANNEL] = self.broadcast_channels.into();
+        } else if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        value[id::TEXT] = self.text.into();
+        if let Some(level) = self.level {
+            value[id::LEVEL] = level.into();
+        }
+        if self.is_mod {
+            value["mod"] = true.into();
+        }
+        if self.is_admin {
+            value["admin"] = true.into();
+        }
+        set_trip(&mut value, self.trip);
+        value[id::TIME] = self.time.0.into();
+        if let Some(message_id) = self.id {
+            value["id"] = message_id.into();
+        }
+        value
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl FromJson for Chat {
-    fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
         if json[id::CMD].as_str() != Some(Self::CMD) {
             return Err(FromJsonError::InvalidCommandField(Self::CMD));
         }
 
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
+        Self::parse_in_place(&mut json)
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl Chat {
+    /// Parse like `from_json`, but give back the json on failure so that it can be logged.
+    /// Everything that can fail is checked before any fields are taken out of it, so it is
+    /// returned unchanged.
+    pub fn try_from_json(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, (FromJsonError, JsonValue)> {
+        if json[id::CMD].as_str() != Some(Self::CMD) {
+            return Err((FromJsonError::InvalidCommandField(Self::CMD), json));
+        }
+
+        match Self::parse_in_place(&mut json) {
+            Ok(chat) => Ok(chat),
+            Err(err) => Err((err, json)),
+        }
+    }
+
+    /// Get just the nick and text of a chat message, borrowed from the json.
+    /// This doesn't allocate or consume anything, for bots that ignore the rest of the message.
+    pub fn parse_minimal(json: &JsonValue) -> Option<(&str, &str)> {
+        if json[id::CMD].as_str() != Some(Self::CMD) {
+            return None;
+        }
+
+        Some((json[id::NICK].as_str()?, json[id::TEXT].as_str()?))
+    }
+
+    fn parse_in_place(json: &mut JsonValue) -> Result<Self, FromJsonError> {
+        const MESSAGE_ID: &str = "id";
         const MOD: &str = "mod";
         const ADMIN: &str = "admin";
 
+        if !json[id::NICK].is_string() {
+            return Err(FromJsonError::InvalidField(id::NICK));
+        }
+        if !json[id::TEXT].is_string() {
+            return Err(FromJsonError::InvalidField(id::TEXT));
+        }
+        let time = Timestamp::from_json(&json[id::TIME])?;
+
         let nick = json[id::NICK]
             .take_string()
             .ok_or(FromJsonError::InvalidField(id::NICK))?;
@@ -313,7 +759,12 @@ impl FromJson for Chat {
             .map(UserType::try_from)
             .and_then(|x| x.map(Some).unwrap_or(None));
         let user_id = json[id::USER_ID].as_u64();
-        let channel = json[id::CHANNEL].take_string();
+        let (channel, broadcast_channels) = if json[id::CHANNEL].is_array() {
+            let channels = as_channel_list(&json[id::CHANNEL]);
+            (channels.first().cloned(), channels)
+        } else {
+            (json[id::CHANNEL].take_string(), Vec::new())
+        };
         let text = json[id::TEXT]
             .take_string()
             .ok_or(FromJsonError::InvalidField(id::TEXT))?;
@@ -321,25 +772,165 @@ impl FromJson for Chat {
         let is_mod = json[MOD].as_bool().unwrap_or(false);
         let is_admin = json[ADMIN].as_bool().unwrap_or(false);
         let trip = Trip::from_json(&mut json[id::TRIP]);
-        let time = Timestamp::from_json(&json[id::TIME])?;
+        let message_id = json[MESSAGE_ID].as_u64();
 
         Ok(Self {
             nick,
             user_type,
             user_id,
-            channel: channel.map(Channel::from),
+            channel,
             text,
             level,
             is_mod,
             is_admin,
             trip: trip.map(Trip::from),
             time,
+            id: message_id,
+            broadcast_channels,
+        })
+    }
+}
+
+/// The chat messages that were sent in `channel`, including broadcasts to it.
+/// Servers only leave out the channel when we can't be in more than one, so messages without
+/// one belong to `default_channel`, the channel that we're in. They are kept only when that is
+/// `channel`, and never when there is no default channel.
+pub fn filter_channel<'a>(
+    chats: &'a [Chat],
+    channel: &str,
+    default_channel: Option<&str>,
+) -> Vec<&'a Chat> {
+    chats
+        .iter()
+        .filter(|chat| {
+            let channels = chat.channels();
+            if channels.is_empty() {
+                default_channel == Some(channel)
+            } else {
+                channels.iter().any(|chat_channel| *chat_channel == channel)
+            }
+        })
+        .collect()
+}
+
+/// Group adjacent chat messages that were sent by the same user, keeping their order.
+/// Messages whose author can't be found in `users` are left out, but still end the group before
+/// them, so the messages on either side are never merged across them.
+pub fn collapse_consecutive<'a>(
+    chats: &'a [Chat],
+    users: &Users,
+) -> Vec<(AccessUserId, Vec<&'a Chat>)> {
+    let mut groups: Vec<(AccessUserId, Vec<&Chat>)> = Vec::new();
+    let mut previous = None;
+    for chat in chats {
+        let author = match chat.user_id {
+            Some(user_id) => Some(AccessUserId::Server(user_id)),
+            None => users.find_online_nick(&chat.nick).map(|(id, _)| id),
+        };
+
+        match (author, groups.last_mut()) {
+            (Some(author), Some((_, group))) if previous == Some(author) => group.push(chat),
+            (Some(author), _) => groups.push((author, vec![chat])),
+            (None, _) => {}
+        }
+        previous = author;
+    }
+    groups
+}
+
+/// A private message from another user.
+/// Legacy servers instead send an `Info` with text like `user whispered: ...`, which is left
+/// to the synthetic module.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Whisper {
+    /// Nickname of the user that sent the whisper.
+    pub nick: Nickname,
+    /// The identifying trip code of the sender.
+    pub trip: MaybeExist<Trip>,
+    /// The content of the whisper.
+    pub text: Text,
+    /// The id of the sender.
+    pub from: Option<UserId>,
+    /// The id of the user that received the whisper.
+    pub to: Option<UserId>,
+    /// The channel it was sent in. V2
+    pub channel: Option<Channel>,
+    pub time: Timestamp,
+}
+impl Command for Whisper {
+    const CMD: &'static str = "whisper";
+}
+impl ServerCommand for Whisper {}
+impl TimedEvent for Whisper {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Whisper {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::NICK] = self.nick.into();
+        set_trip(&mut value, self.trip);
+        value[id::TEXT] = self.text.into();
+        if let Some(from) = self.from {
+            value["from"] = from.into();
+        }
+        if let Some(to) = self.to {
+            value["to"] = to.into();
+        }
+        if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        value[id::TIME] = self.time.0.into();
+        value
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl FromJson for Whisper {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
+        if json[id::CMD].as_str() != Some(Self::CMD) {
+            return Err(FromJsonError::InvalidCommandField(Self::CMD));
+        }
+
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
+        const FROM: &str = "from";
+        const TO: &str = "to";
+
+        let nick = json[id::NICK]
+            .take_string()
+            .ok_or(FromJsonError::InvalidField(id::NICK))?;
+        let trip = Trip::from_json(&mut json[id::TRIP]);
+        let text = json[id::TEXT]
+            .take_string()
+            .ok_or(FromJsonError::InvalidField(id::TEXT))?;
+        let from = json[FROM].as_u64();
+        let to = json[TO].as_u64();
+        let channel = json[id::CHANNEL].take_string();
+        let time = Timestamp::from_json(&json[id::TIME])?;
+        Ok(Self {
+            nick,
+            trip,
+            text,
+            from,
+            to,
+            channel,
+            time,
         })
     }
 }
 
 /// Captcha message to stop spamming bots from joining.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Captcha {
     pub text: Text,
     pub channel: Option<Channel>,
@@ -349,27 +940,44 @@ impl Command for Captcha {
 }
 impl ServerCommand for Captcha {}
 #[cfg(feature = "json_parsing")]
+impl IntoJson for Captcha {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::TEXT] = self.text.into();
+        if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        value
+    }
+}
+#[cfg(feature = "json_parsing")]
 impl FromJson for Captcha {
-    fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
         if json[id::CMD].as_str() != Some(Self::CMD) {
             return Err(FromJsonError::InvalidCommandField(Self::CMD));
         }
 
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
         let text = json[id::TEXT]
             .take_string()
             .ok_or(FromJsonError::InvalidField(id::TEXT))?;
         let channel = json[id::CHANNEL].take_string();
 
-        Ok(Self {
-            text,
-            channel: channel.map(Channel::from),
-        })
+        Ok(Self { text, channel })
     }
 }
 
 /// A /me message
 /// Ex: '@User does jumping jacks'.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Emote {
     pub text: Text,
     pub nick: Option<Nickname>,
@@ -379,23 +987,94 @@ pub struct Emote {
     /// From server
     pub user_id: Option<UserId>,
 }
+impl Emote {
+    /// The nick from the `@nick` at the start of the text.
+    pub fn embedded_nick(&self) -> Option<&str> {
+        self.text.split(' ').next()?.strip_prefix('@')
+    }
+
+    /// Whether the nick at the start of the text agrees with the `nick` field.
+    /// If there is no `nick` field then there is nothing to disagree with.
+    pub fn nick_consistent(&self) -> bool {
+        match &self.nick {
+            Some(nick) => self.embedded_nick() == Some(nick.as_str()),
+            None => true,
+        }
+    }
+
+    /// Parse like `from_json`, but some servers leave out the `time` on emotes, so a missing
+    /// time becomes `Timestamp(0)` rather than an error.
+    /// A `time` that is present but invalid is still an error.
+    #[cfg(feature = "json_parsing")]
+    pub fn from_json_lenient(
+        mut json: JsonValue,
+        server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
+        if json[id::TIME].is_null() {
+            json[id::TIME] = 0.into();
+        }
+        Self::from_json(json, server_api)
+    }
+}
 impl Command for Emote {
     const CMD: &'static str = "emote";
 }
 impl ServerCommand for Emote {}
+impl TimedEvent for Emote {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+impl Authored for Emote {
+    /// The nick field if the server sent one, otherwise the nick at the start of the text.
+    fn author_nick(&self) -> Option<&str> {
+        self.nick.as_deref().or_else(|| self.embedded_nick())
+    }
+
+    fn author_trip(&self) -> MaybeExist<&Trip> {
+        self.trip.as_ref()
+    }
+
+    fn author_id(&self) -> Option<UserId> {
+        self.user_id
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Emote {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::TEXT] = self.text.into();
+        if let Some(nick) = self.nick {
+            value[id::NICK] = nick.into();
+        }
+        value[id::TIME] = self.time.0.into();
+        set_trip(&mut value, self.trip);
+        if let Some(user_id) = self.user_id {
+            value[id::USER_ID] = user_id.into();
+        }
+        value
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl FromJson for Emote {
-    fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
         if json[id::CMD].as_str() != Some(Self::CMD) {
             return Err(FromJsonError::InvalidCommandField(Self::CMD));
         }
 
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
         // TODO: should i strip name prefix?
         let text = json[id::TEXT]
             .take_string()
-            .map(Text::from)
             .ok_or(FromJsonError::InvalidField(id::TEXT))?;
-        let nick = json[id::NICK].take_string().map(Nickname::from);
+        let nick = json[id::NICK].take_string();
         let time = Timestamp::from_json(&json[id::TIME])?;
         let trip = Trip::from_json(&mut json[id::TRIP]);
         let user_id = json[id::USER_ID].as_u64();
@@ -410,6 +1089,7 @@ impl FromJson for Emote {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Invite {
     /// NOTE: This is the channel it was sent in, not the one that is being invited to!
     pub channel: Option<Channel>,
@@ -428,25 +1108,51 @@ impl Command for Invite {
     const CMD: &'static str = "invite";
 }
 impl ServerCommand for Invite {}
+impl TimedEvent for Invite {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Invite {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        value["from"] = self.from.into();
+        value["to"] = self.to.into();
+        value["inviteChannel"] = self.invite_channel.into();
+        value[id::TIME] = self.time.0.into();
+        value
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl FromJson for Invite {
-    fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
         if json[id::CMD].as_str() != Some(Self::CMD) {
             return Err(FromJsonError::InvalidCommandField(Self::CMD));
         }
 
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
         const INVITE_CHANNEL: &str = "inviteChannel";
         const FROM: &str = "from";
         const TO: &str = "to";
 
-        let channel = json[id::CHANNEL].take_string().map(Channel::from);
+        let channel = json[id::CHANNEL].take_string();
         let from = json[FROM]
             .as_u64()
             .ok_or(FromJsonError::InvalidField(FROM))?;
         let to = json[TO].as_u64().ok_or(FromJsonError::InvalidField(TO))?;
         let invite_channel = json[INVITE_CHANNEL]
             .take_string()
-            .map(Channel::from)
             .ok_or(FromJsonError::InvalidField(INVITE_CHANNEL))?;
         let time = Timestamp::from_json(&json[id::TIME])?;
         Ok(Self {
@@ -460,11 +1166,14 @@ impl FromJson for Invite {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct OnlineAdd {
     pub channel: Option<Channel>,
     pub color: Option<Color>,
     pub hash: Option<Hash>,
     pub is_bot: Option<bool>,
+    /// If it is our own user, which the server marks with `isme` when echoing our join.
+    pub is_me: Option<bool>,
     pub level: Option<UserLevel>,
     pub nick: Nickname,
     pub time: Timestamp,
@@ -476,23 +1185,72 @@ impl Command for OnlineAdd {
     const CMD: &'static str = "onlineAdd";
 }
 impl ServerCommand for OnlineAdd {}
+impl TimedEvent for OnlineAdd {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
 #[cfg(feature = "json_parsing")]
-impl FromJson for OnlineAdd {
-    fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
-        if json[id::CMD].as_str() != Some(Self::CMD) {
+impl IntoJson for OnlineAdd {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        if let Some(color) = self.color {
+            value[id::COLOR] = color.to_hex_with_alpha().into();
+        }
+        if let Some(hash) = self.hash {
+            value[id::HASH] = hash.into();
+        }
+        if let Some(is_bot) = self.is_bot {
+            value[id::IS_BOT] = is_bot.into();
+        }
+        if let Some(is_me) = self.is_me {
+            value["isme"] = is_me.into();
+        }
+        if let Some(level) = self.level {
+            value[id::LEVEL] = level.into();
+        }
+        value[id::NICK] = self.nick.into();
+        value[id::TIME] = self.time.0.into();
+        set_trip(&mut value, self.trip);
+        if let Some(user_type) = self.user_type {
+            value[id::USER_TYPE] = user_type.as_str().into();
+        }
+        if let Some(user_id) = self.user_id {
+            value[id::USER_ID] = user_id.into();
+        }
+        value
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl FromJson for OnlineAdd {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
+        if json[id::CMD].as_str() != Some(Self::CMD) {
             return Err(FromJsonError::InvalidCommandField(Self::CMD));
         }
 
-        let channel = json[id::CHANNEL].take_string().map(Channel::from);
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
+        const IS_ME: &str = "isme";
+
+        let channel = json[id::CHANNEL].take_string();
         let color = json[id::COLOR]
             .as_str()
             .and_then(|x| Color::try_from(x).ok());
-        let hash = json[id::HASH].take_string().map(Hash::from);
+        let hash = json[id::HASH].take_string();
         let is_bot = json[id::IS_BOT].as_bool();
+        let is_me = json[IS_ME].as_bool();
         let level = json[id::LEVEL].as_u64();
         let nick = json[id::NICK]
             .take_string()
-            .map(Nickname::from)
             .ok_or(FromJsonError::InvalidField(id::NICK))?;
         let time = Timestamp::from_json(&json[id::TIME])?;
         let trip = Trip::from_json(&mut json[id::TRIP]);
@@ -503,6 +1261,7 @@ impl FromJson for OnlineAdd {
             color,
             hash,
             is_bot,
+            is_me,
             level,
             nick,
             time,
@@ -514,6 +1273,7 @@ impl FromJson for OnlineAdd {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct OnlineRemove {
     pub channel: Option<Channel>,
     pub nick: Nickname,
@@ -524,17 +1284,44 @@ impl Command for OnlineRemove {
     const CMD: &'static str = "onlineRemove";
 }
 impl ServerCommand for OnlineRemove {}
+impl TimedEvent for OnlineRemove {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for OnlineRemove {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        value[id::NICK] = self.nick.into();
+        value[id::TIME] = self.time.0.into();
+        if let Some(user_id) = self.user_id {
+            value[id::USER_ID] = user_id.into();
+        }
+        value
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl FromJson for OnlineRemove {
-    fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
         if json[id::CMD].as_str() != Some(Self::CMD) {
             return Err(FromJsonError::InvalidCommandField(Self::CMD));
         }
 
-        let channel = json[id::CHANNEL].take_string().map(Channel::from);
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
+        let channel = json[id::CHANNEL].take_string();
         let nick = json[id::NICK]
             .take_string()
-            .map(Nickname::from)
             .ok_or(FromJsonError::InvalidField(id::NICK))?;
         let time = Timestamp::from_json(&json[id::TIME])?;
         let user_id = json[id::USER_ID].as_u64();
@@ -548,35 +1335,551 @@ impl FromJson for OnlineRemove {
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Warn {
     pub channel: Option<Channel>,
     pub text: Text,
     pub time: Timestamp,
+    /// The value that was rejected, for servers that send it in a `value` field alongside the
+    /// text.
+    pub value: Option<String>,
+}
+impl Warn {
+    /// The value that the warning is about, such as the nick in `The nick 'Alice' is taken`.
+    /// This is the `value` field if the server sent one, and otherwise the text within the first
+    /// pair of single or double quotes, if there is one.
+    /// Only a quote at the start of a word opens a pair, so the apostrophe in `can't` is skipped.
+    pub fn offending_value(&self) -> Option<&str> {
+        if let Some(value) = &self.value {
+            return Some(value);
+        }
+
+        let mut previous = None;
+        let (start, quote) = self.text.char_indices().find(|&(_, c)| {
+            let at_word_start = match previous {
+                Some(previous) => char::is_whitespace(previous),
+                None => true,
+            };
+            previous = Some(c);
+            at_word_start && (c == '\'' || c == '"')
+        })?;
+        let rest = &self.text[start + 1..];
+        let end = rest.find(quote)?;
+        Some(&rest[..end])
+    }
+
+    /// How long the server asked us to wait, for warns phrased like `wait 30 seconds`.
+    /// Returns `None` if there is no number of seconds in the text.
+    pub fn rate_limit_cooldown(&self) -> Option<Duration> {
+        let words: Vec<&str> = self.text.split_whitespace().collect();
+        words.windows(2).find_map(|pair| {
+            // Covers both `seconds` and `sec`.
+            if pair[1].to_lowercase().starts_with("sec") {
+                pair[0].parse().ok().map(Duration::from_secs)
+            } else {
+                None
+            }
+        })
+    }
 }
 impl Command for Warn {
     const CMD: &'static str = "warn";
 }
 impl ServerCommand for Warn {}
+impl TimedEvent for Warn {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Warn {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        value[id::TEXT] = self.text.into();
+        value[id::TIME] = self.time.0.into();
+        if let Some(rejected) = self.value {
+            value["value"] = rejected.into();
+        }
+        value
+    }
+}
 #[cfg(feature = "json_parsing")]
 impl FromJson for Warn {
     fn from_json(mut json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
-        let channel = json[id::CHANNEL].take_string().map(Channel::from);
+        const VALUE: &str = "value";
+
+        let channel = json[id::CHANNEL].take_string();
         let text = json[id::TEXT]
             .take_string()
-            .map(Text::from)
             .ok_or(FromJsonError::InvalidField(id::TEXT))?;
         let time = Timestamp::from_json(&json[id::TIME])?;
+        let value = json[VALUE].take_string();
         Ok(Self {
             channel,
             text,
             time,
+            value,
+        })
+    }
+}
+
+/// Why the server refused to let us join, read from the `Warn` that it sent instead of the
+/// `OnlineSet`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum JoinError {
+    /// Someone in the channel already has the nick.
+    NickTaken,
+    /// The nick has characters that aren't allowed, or is too long.
+    NickInvalid,
+    /// The channel has as many users as it allows.
+    ChannelFull,
+    /// We joined too often, and have to wait before trying again.
+    RateLimited,
+    /// Some other refusal to join, with the text of the warning.
+    Other(String),
+}
+impl<'a> TryFrom<&'a Warn> for JoinError {
+    type Error = &'a Warn;
+    /// Warns that aren't about joining can't be converted, and are given back so that they can
+    /// be handled some other way.
+    fn try_from(warn: &'a Warn) -> Result<JoinError, &'a Warn> {
+        let text = warn.text.to_lowercase();
+        Ok(
+            if text.contains("nickname taken") || text.contains("is taken") {
+                JoinError::NickTaken
+            } else if text.starts_with("nickname must") || text.contains("invalid nick") {
+                JoinError::NickInvalid
+            } else if text.contains("channel is full") {
+                JoinError::ChannelFull
+            } else if text.contains("joining channels too fast") || text.contains("rate-limited") {
+                JoinError::RateLimited
+            } else if text.starts_with("cannot impersonate") || text.contains("may not join") {
+                JoinError::Other(warn.text.clone())
+            } else {
+                return Err(warn);
+            },
+        )
+    }
+}
+
+/// Statistics about the server, in reply to `client::Stats`.
+/// Servers differ in what they report, so every field is optional.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stats {
+    /// Number of users server wide.
+    pub users: Option<u32>,
+    /// Number of channels with at least a single user server-wide.
+    pub channels: Option<u32>,
+    /// Number of unique ips connected.
+    pub ips: Option<u32>,
+    /// How long the server has been running.
+    pub uptime: Option<u64>,
+    pub time: Option<Timestamp>,
+}
+impl Command for Stats {
+    const CMD: &'static str = "stats";
+}
+impl ServerCommand for Stats {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Stats {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        if let Some(users) = self.users {
+            value["users"] = users.into();
+        }
+        if let Some(channels) = self.channels {
+            value["chans"] = channels.into();
+        }
+        if let Some(ips) = self.ips {
+            value["ips"] = ips.into();
+        }
+        if let Some(uptime) = self.uptime {
+            value["uptime"] = uptime.into();
+        }
+        if let Some(time) = self.time {
+            value[id::TIME] = time.0.into();
+        }
+        value
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl FromJson for Stats {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
+        if json[id::CMD].as_str() != Some(Self::CMD) {
+            return Err(FromJsonError::InvalidCommandField(Self::CMD));
+        }
+
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
+        const USERS: &str = "users";
+        const CHANNELS: &str = "chans";
+        const IPS: &str = "ips";
+        const UPTIME: &str = "uptime";
+
+        let time = if json[id::TIME].is_null() {
+            None
+        } else {
+            Some(Timestamp::from_json(&json[id::TIME])?)
+        };
+        Ok(Self {
+            users: json[USERS].as_u32(),
+            channels: json[CHANNELS].as_u32(),
+            ips: json[IPS].as_u32(),
+            uptime: json[UPTIME].as_u64(),
+            time,
+        })
+    }
+}
+
+/// V2 acknowledgement of a `client::Move` into another channel.
+/// The server follows this with an `OnlineSet` for the new channel.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelChanged {
+    /// The channel that we are now in.
+    pub channel: Channel,
+    pub time: Timestamp,
+}
+impl Command for ChannelChanged {
+    const CMD: &'static str = "changeChannel";
+}
+impl ServerCommand for ChannelChanged {}
+impl TimedEvent for ChannelChanged {
+    fn time(&self) -> Timestamp {
+        self.time
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for ChannelChanged {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::CHANNEL] = self.channel.into();
+        value[id::TIME] = self.time.0.into();
+        value
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl FromJson for ChannelChanged {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
+        if json[id::CMD].as_str() != Some(Self::CMD) {
+            return Err(FromJsonError::InvalidCommandField(Self::CMD));
+        }
+
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
+        let channel = json[id::CHANNEL]
+            .take_string()
+            .ok_or(FromJsonError::InvalidField(id::CHANNEL))?;
+        let time = Timestamp::from_json(&json[id::TIME])?;
+        Ok(Self { channel, time })
+    }
+}
+
+/// V2 Specific
+/// Some of the info about a user changed. Only the fields that changed are sent, so the rest are
+/// `None`/`Unknown`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UpdateUser {
+    pub user_id: UserId,
+    pub channel: Option<Channel>,
+    pub color: Option<Color>,
+    pub trip: MaybeExist<Trip>,
+    pub level: Option<UserLevel>,
+    pub nick: Option<Nickname>,
+    pub time: Option<Timestamp>,
+}
+impl Command for UpdateUser {
+    const CMD: &'static str = "updateUser";
+}
+impl ServerCommand for UpdateUser {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for UpdateUser {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        value[id::USER_ID] = self.user_id.into();
+        if let Some(channel) = self.channel {
+            value[id::CHANNEL] = channel.into();
+        }
+        if let Some(color) = self.color {
+            value[id::COLOR] = color.to_hex_with_alpha().into();
+        }
+        set_trip(&mut value, self.trip);
+        if let Some(level) = self.level {
+            value[id::LEVEL] = level.into();
+        }
+        if let Some(nick) = self.nick {
+            value[id::NICK] = nick.into();
+        }
+        if let Some(time) = self.time {
+            value[id::TIME] = time.0.into();
+        }
+        value
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl FromJson for UpdateUser {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
+        if json[id::CMD].as_str() != Some(Self::CMD) {
+            return Err(FromJsonError::InvalidCommandField(Self::CMD));
+        }
+
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(
+        mut json: JsonValue,
+        _server_api: ServerApi,
+    ) -> Result<Self, FromJsonError> {
+        let user_id = json[id::USER_ID]
+            .as_u64()
+            .ok_or(FromJsonError::InvalidField(id::USER_ID))?;
+        let channel = json[id::CHANNEL].take_string();
+        let color = json[id::COLOR]
+            .as_str()
+            .and_then(|x| Color::try_from(x).ok());
+        let trip = Trip::from_json(&mut json[id::TRIP]);
+        let level = json[id::LEVEL].as_u64();
+        let nick = json[id::NICK].take_string();
+        let time = if json[id::TIME].is_null() {
+            None
+        } else {
+            Some(Timestamp::from_json(&json[id::TIME])?)
+        };
+        Ok(Self {
+            user_id,
+            channel,
+            color,
+            trip,
+            level,
+            nick,
+            time,
         })
     }
 }
 
+/// Keepalive acknowledgement in reply to a `client::Ping`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Pong {
+    /// Not every server bothers to include the time.
+    pub time: Option<Timestamp>,
+}
+impl Command for Pong {
+    const CMD: &'static str = "pong";
+}
+impl ServerCommand for Pong {}
+#[cfg(feature = "json_parsing")]
+impl IntoJson for Pong {
+    fn into_json(self, _server_api: ServerApi) -> JsonValue {
+        let mut value = object! {};
+        value[id::CMD] = Self::CMD.into();
+        if let Some(time) = self.time {
+            value[id::TIME] = time.0.into();
+        }
+        value
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl FromJson for Pong {
+    fn from_json(json: JsonValue, server_api: ServerApi) -> Result<Self, FromJsonError> {
+        if json[id::CMD].as_str() != Some(Self::CMD) {
+            return Err(FromJsonError::InvalidCommandField(Self::CMD));
+        }
+
+        Self::from_json_nested(json, server_api)
+    }
+
+    fn from_json_nested(json: JsonValue, _server_api: ServerApi) -> Result<Self, FromJsonError> {
+        let time = if json[id::TIME].is_null() {
+            None
+        } else {
+            Some(Timestamp::from_json(&json[id::TIME])?)
+        };
+        Ok(Self { time })
+    }
+}
+
+/// Any of the commands that the server may send.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ServerMessage {
+    OnlineSet(OnlineSet),
+    Session(Session),
+    Info(Info),
+    Chat(Chat),
+    Captcha(Captcha),
+    Emote(Emote),
+    Invite(Invite),
+    OnlineAdd(OnlineAdd),
+    OnlineRemove(OnlineRemove),
+    Warn(Warn),
+    Pong(Pong),
+    Stats(Stats),
+    ChannelChanged(ChannelChanged),
+    Whisper(Whisper),
+    UpdateUser(UpdateUser),
+    /// A command that we don't know of, kept so that it can be logged.
+    /// This is skipped by serde, as it has no form for raw json.
+    #[cfg(feature = "json_parsing")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Unknown(JsonValue),
+}
+impl ServerMessage {
+    /// The name of the command, as in its `cmd` field.
+    pub fn cmd_name(&self) -> &str {
+        match self {
+            ServerMessage::OnlineSet(_) => OnlineSet::CMD,
+            ServerMessage::Session(_) => Session::CMD,
+            ServerMessage::Info(_) => Info::CMD,
+            ServerMessage::Chat(_) => Chat::CMD,
+            ServerMessage::Captcha(_) => Captcha::CMD,
+            ServerMessage::Emote(_) => Emote::CMD,
+            ServerMessage::Invite(_) => Invite::CMD,
+            ServerMessage::OnlineAdd(_) => OnlineAdd::CMD,
+            ServerMessage::OnlineRemove(_) => OnlineRemove::CMD,
+            ServerMessage::Warn(_) => Warn::CMD,
+            ServerMessage::Pong(_) => Pong::CMD,
+            ServerMessage::Stats(_) => Stats::CMD,
+            ServerMessage::ChannelChanged(_) => ChannelChanged::CMD,
+            ServerMessage::Whisper(_) => Whisper::CMD,
+            ServerMessage::UpdateUser(_) => UpdateUser::CMD,
+            #[cfg(feature = "json_parsing")]
+            ServerMessage::Unknown(json) => json[id::CMD].as_str().unwrap_or(""),
+        }
+    }
+
+    /// Whether the command is only ever sent by the server.
+    /// Commands like `chat` and `session` share their name with a client command, so a proxy
+    /// can't tell the direction from the name alone. Unknown commands might be either.
+    pub fn is_server_only(&self) -> bool {
+        #[cfg(feature = "json_parsing")]
+        if let ServerMessage::Unknown(_) = self {
+            return false;
+        }
+
+        !matches!(
+            self,
+            ServerMessage::Session(_)
+                | ServerMessage::Chat(_)
+                | ServerMessage::Emote(_)
+                | ServerMessage::Invite(_)
+                | ServerMessage::Stats(_)
+                | ServerMessage::Whisper(_)
+        )
+    }
+}
+
+/// Parse any message from the server, deciding what it is by its `cmd` field.
+/// Commands that we don't know of become `ServerMessage::Unknown` rather than an error.
+#[cfg(feature = "json_parsing")]
+pub fn parse(json: JsonValue, server_api: ServerApi) -> Result<ServerMessage, FromJsonError> {
+    let cmd = match json[id::CMD].as_str() {
+        Some(cmd) => cmd,
+        None => return Err(FromJsonError::InvalidField(id::CMD)),
+    };
+    Ok(match cmd {
+        OnlineSet::CMD => ServerMessage::OnlineSet(OnlineSet::from_json(json, server_api)?),
+        Session::CMD => ServerMessage::Session(Session::from_json(json, server_api)?),
+        Info::CMD => ServerMessage::Info(Info::from_json(json, server_api)?),
+        Chat::CMD => ServerMessage::Chat(Chat::from_json(json, server_api)?),
+        Captcha::CMD => ServerMessage::Captcha(Captcha::from_json(json, server_api)?),
+        Emote::CMD => ServerMessage::Emote(Emote::from_json(json, server_api)?),
+        Invite::CMD => ServerMessage::Invite(Invite::from_json(json, server_api)?),
+        OnlineAdd::CMD => ServerMessage::OnlineAdd(OnlineAdd::from_json(json, server_api)?),
+        OnlineRemove::CMD => {
+            ServerMessage::OnlineRemove(OnlineRemove::from_json(json, server_api)?)
+        }
+        Warn::CMD => ServerMessage::Warn(Warn::from_json(json, server_api)?),
+        Pong::CMD => ServerMessage::Pong(Pong::from_json(json, server_api)?),
+        Stats::CMD => ServerMessage::Stats(Stats::from_json(json, server_api)?),
+        ChannelChanged::CMD => {
+            ServerMessage::ChannelChanged(ChannelChanged::from_json(json, server_api)?)
+        }
+        Whisper::CMD => ServerMessage::Whisper(Whisper::from_json(json, server_api)?),
+        UpdateUser::CMD => ServerMessage::UpdateUser(UpdateUser::from_json(json, server_api)?),
+        _ => {
+            crate::util::log_unknown_command(cmd);
+            ServerMessage::Unknown(json)
+        }
+    })
+}
+
+/// A one line summary of the message, for logging.
+impl std::fmt::Display for ServerMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.cmd_name())?;
+        match self {
+            ServerMessage::OnlineSet(online_set) => {
+                let count = online_set
+                    .nicks
+                    .as_ref()
+                    .map(Vec::len)
+                    .or_else(|| online_set.users.as_ref().map(Vec::len))
+                    .unwrap_or(0);
+                write!(f, " {} users", count)?;
+                if let Some(channel) = &online_set.channel {
+                    write!(f, " in #{}", channel)?;
+                }
+                Ok(())
+            }
+            ServerMessage::Session(session) => write!(
+                f,
+                " {} users in {} channels",
+                session.users, session.channels
+            ),
+            ServerMessage::Info(info) => write!(f, " {}", info.text),
+            ServerMessage::Chat(chat) => write!(f, " {}: {}", chat.nick, chat.text),
+            ServerMessage::Captcha(_) | ServerMessage::Pong(_) | ServerMessage::Stats(_) => Ok(()),
+            ServerMessage::Emote(emote) => write!(f, " {}", emote.text),
+            ServerMessage::Invite(invite) => write!(
+                f,
+                " {} invited {} to #{}",
+                invite.from, invite.to, invite.invite_channel
+            ),
+            ServerMessage::OnlineAdd(online_add) => {
+                write!(f, " {} joined", online_add.nick)?;
+                if let Some(channel) = &online_add.channel {
+                    write!(f, " #{}", channel)?;
+                }
+                Ok(())
+            }
+            ServerMessage::OnlineRemove(online_remove) => {
+                write!(f, " {} left", online_remove.nick)?;
+                if let Some(channel) = &online_remove.channel {
+                    write!(f, " #{}", channel)?;
+                }
+                Ok(())
+            }
+            ServerMessage::Warn(warn) => write!(f, " {}", warn.text),
+            ServerMessage::ChannelChanged(changed) => write!(f, " #{}", changed.channel),
+            ServerMessage::Whisper(whisper) => write!(f, " {}: {}", whisper.nick, whisper.text),
+            ServerMessage::UpdateUser(update) => write!(f, " {}", update.user_id),
+            #[cfg(feature = "json_parsing")]
+            ServerMessage::Unknown(_) => write!(f, " (unknown)"),
+        }
+    }
+}
+
 /// Structures of commands that are joined together
 pub mod synthetic {
-    use crate::{AccessUserId, Channel, Text, Timestamp, Users};
+    use crate::{AccessUserId, Channel, Nickname, Text, Timestamp, Users};
 
     #[derive(Debug, Clone)]
     pub enum InviteConversionError {
@@ -596,7 +1899,25 @@ pub mod synthetic {
         UnknownNick,
         /// We don't know the id of self.
         UnknownSelf,
+        /// Both the inviter and the invited were ourself, which means the text was malformed.
+        SelfInvite,
+    }
+    impl std::fmt::Display for InviteConversionError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(match self {
+                InviteConversionError::NoFrom => "invite has no inviting user",
+                InviteConversionError::NoInvited => "invite is missing the 'invited' text",
+                InviteConversionError::NoTo => "invite has no invited user",
+                InviteConversionError::NoToJoiner => "invite is missing the 'to' text",
+                InviteConversionError::NoChannel => "invite has no channel",
+                InviteConversionError::InvalidChannel => "invite has an invalid channel",
+                InviteConversionError::UnknownNick => "invite names a user that isn't known",
+                InviteConversionError::UnknownSelf => "our own user isn't known",
+                InviteConversionError::SelfInvite => "invite is from ourself to ourself",
+            })
+        }
     }
+    impl std::error::Error for InviteConversionError {}
 
     #[derive(Debug, Clone, Eq, PartialEq)]
     pub struct Invite {
@@ -624,7 +1945,7 @@ pub mod synthetic {
 
         pub fn from_info(users: &Users, info: &super::Info) -> Result<Self, InviteConversionError> {
             // TODO: handle empty parts of the text
-            let mut split = info.text.splitn(4, ' ');
+            let mut split = info.text.splitn(5, ' ');
             let from = split.next().ok_or(InviteConversionError::NoFrom)?;
 
             if split.next() != Some("invited") {
@@ -661,6 +1982,9 @@ pub mod synthetic {
                 let from = users.ourself().ok_or(InviteConversionError::UnknownSelf)?;
                 (to, from)
             };
+            if to == from {
+                return Err(InviteConversionError::SelfInvite);
+            }
             Ok(Self {
                 from,
                 to,
@@ -670,11 +1994,28 @@ pub mod synthetic {
         }
     }
 
+    /// A private message sent to us.
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    pub struct Whisper {
+        /// Nickname of the user that whispered.
+        pub from: Nickname,
+        pub text: Text,
+        pub time: Timestamp,
+    }
+
     #[derive(Debug, Clone)]
     pub enum EmoteConversionError {
         /// Failed to find the user
         NoUserFound,
     }
+    impl std::fmt::Display for EmoteConversionError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(match self {
+                EmoteConversionError::NoUserFound => "emote is from a user that isn't known",
+            })
+        }
+    }
+    impl std::error::Error for EmoteConversionError {}
 
     #[derive(Debug, Clone)]
     pub enum EmoteInfoConversionError {
@@ -684,7 +2025,22 @@ pub mod synthetic {
         NoAt,
         /// Failed to find the user
         NoUserFound,
+        /// More than one online user has the nick, so we can't tell who it was.
+        AmbiguousNick,
+    }
+    impl std::fmt::Display for EmoteInfoConversionError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(match self {
+                EmoteInfoConversionError::NoUser => "emote has no user",
+                EmoteInfoConversionError::NoAt => "emote text doesn't start with '@'",
+                EmoteInfoConversionError::NoUserFound => "emote is from a user that isn't known",
+                EmoteInfoConversionError::AmbiguousNick => {
+                    "emote nick is shared by more than one online user"
+                }
+            })
+        }
     }
+    impl std::error::Error for EmoteInfoConversionError {}
 
     #[derive(Debug, Clone, Eq, PartialEq)]
     pub struct Emote {
@@ -706,8 +2062,7 @@ pub mod synthetic {
                     emote
                         .nick
                         .as_ref()
-                        .map(|nick| users.find_online_nick(&nick).map(|x| x.0))
-                        .flatten()
+                        .and_then(|nick| users.find_online_nick(nick).map(|x| x.0))
                 })
                 .ok_or(EmoteConversionError::NoUserFound)?;
             let time = emote.time;
@@ -728,15 +2083,13 @@ pub mod synthetic {
                 .strip_prefix('@')
                 .ok_or(EmoteInfoConversionError::NoAt)?;
 
-            let user_id = users
-                .find_online_nick(from)
-                .map(|x| x.0)
-                .ok_or(EmoteInfoConversionError::NoUserFound)?;
+            let user_id = match users.find_online_nick_all(from).as_slice() {
+                [] => return Err(EmoteInfoConversionError::NoUserFound),
+                [id] => *id,
+                _ => return Err(EmoteInfoConversionError::AmbiguousNick),
+            };
 
-            let text = split
-                .next()
-                .map(|x| x.to_string())
-                .unwrap_or_else(String::new);
+            let text = split.next().map(|x| x.to_string()).unwrap_or_default();
 
             Ok(Self {
                 text,
@@ -746,3 +2099,1172 @@ pub mod synthetic {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn pong_tolerates_missing_time() {
+        let pong = Pong::from_json(json::object! { cmd: "pong" }, ServerApi::HackChatV2).unwrap();
+        assert_eq!(pong, Pong { time: None });
+
+        let pong = Pong::from_json(
+            json::object! { cmd: "pong", time: 1680000000000u64 },
+            ServerApi::HackChatV2,
+        )
+        .unwrap();
+        assert_eq!(pong.time, Some(Timestamp(1680000000000)));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn cmd_name_and_direction() {
+        let pong = parse(json::object! { cmd: "pong" }, ServerApi::HackChatV2).unwrap();
+        assert_eq!(pong.cmd_name(), "pong");
+        assert!(pong.is_server_only());
+
+        let chat = parse(
+            json::object! { cmd: "chat", nick: "alice", text: "hi", time: 1 },
+            ServerApi::HackChatV2,
+        )
+        .unwrap();
+        assert_eq!(chat.cmd_name(), "chat");
+        assert!(!chat.is_server_only());
+
+        let unknown = parse(json::object! { cmd: "somethingNew" }, ServerApi::HackChatV2).unwrap();
+        assert_eq!(unknown.cmd_name(), "somethingNew");
+        assert!(!unknown.is_server_only());
+    }
+
+    fn session(restored: Option<bool>) -> Session {
+        Session {
+            users: 10,
+            channels: 2,
+            public: PublicChannels::default(),
+            session_id: "abcdef".to_owned(),
+            restored,
+            time: Timestamp(0),
+        }
+    }
+
+    #[test]
+    fn resumed_id_only_when_restored() {
+        assert_eq!(
+            session(Some(true)).resumed_id().map(String::as_str),
+            Some("abcdef")
+        );
+        assert_eq!(session(Some(false)).resumed_id(), None);
+        assert_eq!(session(None).resumed_id(), None);
+    }
+
+    fn chat(nick: &str, user_id: Option<UserId>, text: &str) -> Chat {
+        Chat {
+            nick: nick.to_owned(),
+            user_type: None,
+            user_id,
+            channel: None,
+            text: text.to_owned(),
+            level: None,
+            is_mod: false,
+            is_admin: false,
+            trip: MaybeExist::Not,
+            time: Timestamp(0),
+            id: None,
+            broadcast_channels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn collapse_breaks_on_author_change() {
+        let chats = [
+            chat("a", Some(1), "1"),
+            chat("a", Some(1), "2"),
+            chat("b", Some(2), "3"),
+            chat("a", Some(1), "4"),
+        ];
+        let groups = collapse_consecutive(&chats, &Users::default());
+        let groups: Vec<(AccessUserId, Vec<&str>)> = groups
+            .into_iter()
+            .map(|(id, group)| (id, group.iter().map(|chat| chat.text.as_str()).collect()))
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                (AccessUserId::Server(1), vec!["1", "2"]),
+                (AccessUserId::Server(2), vec!["3"]),
+                (AccessUserId::Server(1), vec!["4"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn collapse_unknown_author_ends_group() {
+        let chats = [
+            chat("a", Some(1), "1"),
+            chat("stranger", None, "2"),
+            chat("a", Some(1), "3"),
+        ];
+        let groups = collapse_consecutive(&chats, &Users::default());
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].1[0].text, "1");
+        assert_eq!(groups[1].1[0].text, "3");
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn nested_chat_without_cmd() {
+        let json = json::object! { nick: "alice", text: "hi", time: 5 };
+        assert_eq!(
+            Chat::from_json(json.clone(), ServerApi::HackChatV2),
+            Err(FromJsonError::InvalidCommandField(Chat::CMD))
+        );
+
+        let chat = Chat::from_json_nested(json, ServerApi::HackChatV2).unwrap();
+        assert_eq!(chat.nick, "alice");
+        assert_eq!(chat.text, "hi");
+        assert_eq!(chat.time, Timestamp(5));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn online_set_mixed_users() {
+        let json = json::object! {
+            cmd: "onlineSet",
+            text: "lounge",
+            time: 1,
+            users: [
+                "alice",
+                { channel: "lounge", nick: "bob", hash: "xyz", userid: 2, isme: true },
+            ],
+        };
+        let online_set = OnlineSet::from_json(json, ServerApi::HackChatPreV2).unwrap();
+        let users = online_set.users.unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].nick, "alice");
+        assert_eq!(users[0].channel, "lounge");
+        assert_eq!(users[0].user_id, None);
+        assert_eq!(users[1].nick, "bob");
+        assert_eq!(users[1].user_id, Some(2));
+        assert_eq!(users[1].is_me, Some(true));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn emote_without_time() {
+        let json = json::object! { cmd: "emote", nick: "alice", text: "@alice waves" };
+        assert_eq!(
+            Emote::from_json(json.clone(), ServerApi::HackChatV2),
+            Err(FromJsonError::InvalidField(id::TIME))
+        );
+
+        let emote = Emote::from_json_lenient(json, ServerApi::HackChatV2).unwrap();
+        assert_eq!(emote.time, Timestamp(0));
+        assert_eq!(emote.text, "@alice waves");
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn session_public_channels() {
+        let json = json::object! {
+            cmd: "session",
+            users: 12,
+            chans: 3,
+            public: { lounge: 9, programming: 3 },
+            sessionID: "abcdef",
+            restored: false,
+            time: 1,
+        };
+        let session = Session::from_json(json, ServerApi::HackChatV2).unwrap();
+        assert_eq!(session.public.len(), 2);
+        assert_eq!(session.public.get("lounge"), Some(&9));
+        assert_eq!(session.public.get("programming"), Some(&3));
+
+        let json = json::object! {
+            cmd: "session",
+            users: 12,
+            chans: 3,
+            public: { lounge: "many" },
+            sessionID: "abcdef",
+            time: 1,
+        };
+        assert_eq!(
+            Session::from_json(json, ServerApi::HackChatV2),
+            Err(FromJsonError::InvalidField("public"))
+        );
+    }
+
+    fn emote(nick: Option<&str>, text: &str) -> Emote {
+        Emote {
+            text: text.to_owned(),
+            nick: nick.map(str::to_owned),
+            time: Timestamp(0),
+            trip: MaybeExist::Unknown,
+            user_id: None,
+        }
+    }
+
+    #[test]
+    fn emote_nick_consistency() {
+        let consistent = emote(Some("alice"), "@alice waves");
+        assert_eq!(consistent.embedded_nick(), Some("alice"));
+        assert!(consistent.nick_consistent());
+
+        let inconsistent = emote(Some("bob"), "@alice waves");
+        assert!(!inconsistent.nick_consistent());
+
+        let no_prefix = emote(Some("alice"), "waves");
+        assert_eq!(no_prefix.embedded_nick(), None);
+        assert!(!no_prefix.nick_consistent());
+
+        assert!(emote(None, "@alice waves").nick_consistent());
+    }
+
+    #[test]
+    fn chat_as_whisper() {
+        let whisper = chat("alice", None, "whispered: psst").as_whisper().unwrap();
+        assert_eq!(whisper.from, "alice");
+        assert_eq!(whisper.text, "psst");
+
+        assert!(chat("alice", None, "hello there").as_whisper().is_none());
+    }
+
+    #[cfg(all(feature = "json_parsing", feature = "indexmap"))]
+    #[test]
+    fn session_public_keeps_order() {
+        let json = json::object! {
+            cmd: "session",
+            users: 12,
+            chans: 3,
+            public: { zebra: 1, lounge: 9, apple: 3 },
+            sessionID: "abcdef",
+            time: 1,
+        };
+        let session = Session::from_json(json, ServerApi::HackChatV2).unwrap();
+        let channels: Vec<&str> = session.public.keys().map(String::as_str).collect();
+        assert_eq!(channels, ["zebra", "lounge", "apple"]);
+    }
+
+    fn warn(text: &str) -> Warn {
+        Warn {
+            channel: None,
+            text: text.to_owned(),
+            time: Timestamp(0),
+            value: None,
+        }
+    }
+
+    #[test]
+    fn warn_offending_value() {
+        assert_eq!(
+            warn("Nickname taken: \"Alice\"").offending_value(),
+            Some("Alice")
+        );
+        assert_eq!(
+            warn("The nick 'Alice' is already taken.").offending_value(),
+            Some("Alice")
+        );
+        assert_eq!(
+            warn("You can't join 'secret' right now.").offending_value(),
+            Some("secret")
+        );
+        assert_eq!(warn("'bob' is banned").offending_value(), Some("bob"));
+        assert_eq!(warn("You're sending too fast.").offending_value(), None);
+        assert_eq!(warn("Nothing here").offending_value(), None);
+    }
+
+    #[test]
+    fn online_set_me() {
+        let mut online_set = OnlineSet {
+            nicks: None,
+            users: Some(vec![
+                OnlineSetUser::from_nick("alice".to_owned(), "lounge".to_owned()),
+                OnlineSetUser {
+                    is_me: Some(true),
+                    ..OnlineSetUser::from_nick("bob".to_owned(), "lounge".to_owned())
+                },
+            ]),
+            channel: Some("lounge".to_owned()),
+            time: Timestamp(0),
+        };
+        assert_eq!(online_set.me().map(|user| user.nick.as_str()), Some("bob"));
+
+        online_set.users.as_mut().unwrap()[1].is_me = Some(false);
+        assert_eq!(online_set.me(), None);
+    }
+
+    #[test]
+    fn sort_timed_events() {
+        use crate::util::TimedEvent;
+
+        let mut events: Vec<Box<dyn TimedEvent>> = vec![
+            Box::new(Chat {
+                time: Timestamp(30),
+                ..chat("alice", None, "hi")
+            }),
+            Box::new(Warn {
+                time: Timestamp(10),
+                ..warn("slow down")
+            }),
+            Box::new(Emote {
+                time: Timestamp(20),
+                ..emote(None, "@alice waves")
+            }),
+        ];
+        events.sort_by_key(|event| event.time());
+        let times: Vec<u64> = events.iter().map(|event| event.time().0).collect();
+        assert_eq!(times, [10, 20, 30]);
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn partial_stats() {
+        let stats = Stats::from_json(
+            json::object! { cmd: "stats", users: 40, ips: 31 },
+            ServerApi::HackChatLegacy,
+        )
+        .unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                users: Some(40),
+                channels: None,
+                ips: Some(31),
+                uptime: None,
+                time: None,
+            }
+        );
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn channel_changed_ack() {
+        let json = json::object! { cmd: "changeChannel", channel: "elsewhere", time: 7 };
+        assert_eq!(
+            ChannelChanged::from_json(json, ServerApi::HackChatV2),
+            Ok(ChannelChanged {
+                channel: "elsewhere".to_owned(),
+                time: Timestamp(7),
+            })
+        );
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn chat_message_id() {
+        let json = json::object! { cmd: "chat", nick: "alice", text: "hi", time: 1, id: 42 };
+        let chat = Chat::from_json(json, ServerApi::HackChatV2).unwrap();
+        assert_eq!(chat.message_id(), Some(42));
+
+        let json = json::object! { cmd: "chat", nick: "alice", text: "hi", time: 1 };
+        let chat = Chat::from_json(json, ServerApi::HackChatLegacy).unwrap();
+        assert_eq!(chat.message_id(), None);
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn try_from_json_returns_value() {
+        let json = json::object! { cmd: "chat", nick: "alice", time: 1 };
+        let (err, value) = Chat::try_from_json(json.clone(), ServerApi::HackChatV2).unwrap_err();
+        assert_eq!(err, FromJsonError::InvalidField(id::TEXT));
+        assert_eq!(value, json);
+    }
+
+    #[test]
+    fn info_mentioned_channels() {
+        let info = Info {
+            text: "alice invited you to ?secret, also see ?lounge. ? alone".to_owned(),
+            channel: None,
+            time: Timestamp(0),
+        };
+        assert_eq!(info.mentioned_channels(), ["secret", "lounge"]);
+    }
+
+    #[test]
+    fn server_message_summary() {
+        let message = ServerMessage::Chat(chat("Alice", None, "hello"));
+        assert_eq!(message.to_string(), "chat Alice: hello");
+
+        let message = ServerMessage::OnlineAdd(OnlineAdd {
+            channel: Some("lounge".to_owned()),
+            color: None,
+            hash: None,
+            is_bot: None,
+            is_me: None,
+            level: None,
+            nick: "Bob".to_owned(),
+            time: Timestamp(0),
+            trip: MaybeExist::Unknown,
+            user_type: None,
+            user_id: None,
+        });
+        assert_eq!(message.to_string(), "onlineAdd Bob joined #lounge");
+
+        assert_eq!(ServerMessage::Pong(Pong { time: None }).to_string(), "pong");
+    }
+
+    #[test]
+    fn online_set_user_effective_type() {
+        let user = OnlineSetUser {
+            user_type: Some(UserType::User),
+            level: Some(MOD_LEVEL),
+            ..OnlineSetUser::from_nick("alice".to_owned(), "lounge".to_owned())
+        };
+        assert_eq!(user.effective_user_type(), UserType::Mod);
+
+        let user = OnlineSetUser {
+            user_type: Some(UserType::Admin),
+            ..OnlineSetUser::from_nick("bob".to_owned(), "lounge".to_owned())
+        };
+        assert_eq!(user.effective_user_type(), UserType::Admin);
+
+        let user = OnlineSetUser::from_nick("carol".to_owned(), "lounge".to_owned());
+        assert_eq!(user.effective_user_type(), UserType::User);
+    }
+
+    #[cfg(all(feature = "json_parsing", feature = "tracing"))]
+    #[test]
+    fn unknown_command_is_logged() {
+        use std::sync::{Arc, Mutex};
+        use tracing::{field::Field, span, Event, Level, Metadata, Subscriber};
+
+        /// Keeps the `cmd` field of every debug event.
+        struct Capture(Arc<Mutex<Vec<String>>>);
+        struct CmdVisitor<'a>(&'a mut Vec<String>);
+        impl tracing::field::Visit for CmdVisitor<'_> {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                if field.name() == "cmd" {
+                    self.0.push(value.to_owned());
+                }
+            }
+
+            fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+        }
+        impl Subscriber for Capture {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+
+            fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+            fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                if *event.metadata().level() == Level::DEBUG {
+                    event.record(&mut CmdVisitor(&mut self.0.lock().unwrap()));
+                }
+            }
+
+            fn enter(&self, _span: &span::Id) {}
+
+            fn exit(&self, _span: &span::Id) {}
+        }
+
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Capture(logged.clone()), || {
+            parse(json::object! { cmd: "somethingNew" }, ServerApi::HackChatV2).unwrap();
+            parse(json::object! { cmd: "pong" }, ServerApi::HackChatV2).unwrap();
+        });
+        assert_eq!(*logged.lock().unwrap(), ["somethingNew"]);
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn chat_parse_minimal() {
+        let json = json::object! { cmd: "chat", nick: "alice", text: "hi", time: 1 };
+        assert_eq!(Chat::parse_minimal(&json), Some(("alice", "hi")));
+        assert_eq!(json["text"].as_str(), Some("hi"));
+
+        assert_eq!(
+            Chat::parse_minimal(&json::object! { cmd: "emote", nick: "alice", text: "hi" }),
+            None
+        );
+        assert_eq!(
+            Chat::parse_minimal(&json::object! { cmd: "chat", nick: "alice" }),
+            None
+        );
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn whisper_parse() {
+        let json = json::object! {
+            cmd: "whisper",
+            nick: "alice",
+            trip: "abc123",
+            text: "psst",
+            from: 1,
+            to: 2,
+            channel: "lounge",
+            time: 5,
+        };
+        assert_eq!(
+            Whisper::from_json(json, ServerApi::HackChatV2),
+            Ok(Whisper {
+                nick: "alice".to_owned(),
+                trip: MaybeExist::Has(Trip("abc123".to_owned())),
+                text: "psst".to_owned(),
+                from: Some(1),
+                to: Some(2),
+                channel: Some("lounge".to_owned()),
+                time: Timestamp(5),
+            })
+        );
+
+        let json = json::object! { cmd: "whisper", nick: "alice", time: 5 };
+        assert_eq!(
+            Whisper::from_json(json, ServerApi::HackChatV2),
+            Err(FromJsonError::InvalidField(id::TEXT))
+        );
+    }
+
+    fn online_add(nick: &str, user_id: Option<UserId>) -> OnlineAdd {
+        OnlineAdd {
+            channel: Some("lounge".to_owned()),
+            color: None,
+            hash: None,
+            is_bot: None,
+            is_me: None,
+            level: None,
+            nick: nick.to_owned(),
+            time: Timestamp(0),
+            trip: MaybeExist::Unknown,
+            user_type: None,
+            user_id,
+        }
+    }
+
+    fn info(text: &str) -> Info {
+        Info {
+            text: text.to_owned(),
+            channel: None,
+            time: Timestamp(0),
+        }
+    }
+
+    #[test]
+    fn self_invite_is_rejected() {
+        let mut users = Users::with_self(AccessUserId::Server(1));
+        users.apply_online_add(&online_add("alice", Some(1)));
+        users.apply_online_add(&online_add("bob", Some(2)));
+
+        let result = synthetic::Invite::from_info(&users, &info("alice invited you to ?secret"));
+        assert!(matches!(
+            result,
+            Err(synthetic::InviteConversionError::SelfInvite)
+        ));
+
+        let invite =
+            synthetic::Invite::from_info(&users, &info("bob invited you to ?secret")).unwrap();
+        assert_eq!(invite.from, AccessUserId::Server(2));
+        assert_eq!(invite.to, AccessUserId::Server(1));
+        assert_eq!(invite.invite_channel, "secret");
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn partial_update_user() {
+        let json = json::object! { cmd: "updateUser", userid: 3, color: "ff0000" };
+        assert_eq!(
+            UpdateUser::from_json(json, ServerApi::HackChatV2),
+            Ok(UpdateUser {
+                user_id: 3,
+                channel: None,
+                color: Some(Color::RED),
+                trip: MaybeExist::Unknown,
+                level: None,
+                nick: None,
+                time: None,
+            })
+        );
+
+        let json = json::object! { cmd: "updateUser", color: "ff0000" };
+        assert_eq!(
+            UpdateUser::from_json(json, ServerApi::HackChatV2),
+            Err(FromJsonError::InvalidField(id::USER_ID))
+        );
+    }
+
+    #[test]
+    fn emote_info_with_duplicate_nicks() {
+        let mut users = Users::default();
+        users.apply_online_add(&online_add("alice", Some(1)));
+        users.apply_online_add(&online_add("bob", Some(2)));
+
+        let emote = synthetic::Emote::from_info(&users, &info("@alice waves")).unwrap();
+        assert_eq!(emote.user_id, AccessUserId::Server(1));
+        assert_eq!(emote.text, "waves");
+
+        users.apply_online_add(&online_add("alice", Some(3)));
+        assert!(matches!(
+            synthetic::Emote::from_info(&users, &info("@alice waves")),
+            Err(synthetic::EmoteInfoConversionError::AmbiguousNick)
+        ));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn parse_dispatches_on_cmd() {
+        let api = ServerApi::HackChatV2;
+        assert!(matches!(
+            parse(json::object! { cmd: "info", text: "hello", time: 1 }, api),
+            Ok(ServerMessage::Info(_))
+        ));
+        assert!(matches!(
+            parse(
+                json::object! { cmd: "warn", text: "slow down", time: 1 },
+                api
+            ),
+            Ok(ServerMessage::Warn(_))
+        ));
+        assert!(matches!(
+            parse(json::object! { cmd: "somethingNew", value: 1 }, api),
+            Ok(ServerMessage::Unknown(_))
+        ));
+        assert_eq!(
+            parse(json::object! { text: "hello" }, api),
+            Err(FromJsonError::InvalidField(id::CMD))
+        );
+        assert!(parse(json::object! { cmd: "chat", time: 1 }, api).is_err());
+    }
+
+    #[test]
+    fn chat_decoded_text() {
+        let escaped = chat("alice", None, "&lt;b&gt; &amp; c");
+        assert_eq!(escaped.decoded_text(), "<b> & c");
+        assert_eq!(escaped.text, "&lt;b&gt; &amp; c");
+
+        // Decoding is a single pass, so escaped entities stay escaped once.
+        let escaped = chat("alice", None, "&amp;lt; &quot;x&quot; &#39;y&#39;");
+        assert_eq!(escaped.decoded_text(), "&lt; \"x\" 'y'");
+    }
+
+    #[test]
+    fn authored_chat_and_emote() {
+        use crate::util::Authored;
+
+        fn describe(authored: &dyn Authored) -> (Option<&str>, Option<&Trip>, Option<UserId>) {
+            (
+                authored.author_nick(),
+                authored.author_trip().into(),
+                authored.author_id(),
+            )
+        }
+
+        let trip = Trip("abc123".to_owned());
+        let message = Chat {
+            trip: MaybeExist::Has(trip.clone()),
+            ..chat("alice", Some(1), "hi")
+        };
+        assert_eq!(describe(&message), (Some("alice"), Some(&trip), Some(1)));
+
+        let action = emote(None, "@bob waves");
+        assert_eq!(describe(&action), (Some("bob"), None, None));
+    }
+
+    #[test]
+    fn synthetic_errors_display() {
+        fn convert(
+            users: &Users,
+            text: &str,
+        ) -> Result<synthetic::Invite, Box<dyn std::error::Error>> {
+            Ok(synthetic::Invite::from_info(users, &info(text))?)
+        }
+
+        let err = convert(&Users::default(), "bob invited you to ?secret").unwrap_err();
+        assert_eq!(err.to_string(), "our own user isn't known");
+        assert_eq!(
+            synthetic::EmoteConversionError::NoUserFound.to_string(),
+            "emote is from a user that isn't known"
+        );
+        assert!(!synthetic::EmoteInfoConversionError::AmbiguousNick
+            .to_string()
+            .is_empty());
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn into_json_omits_absent_trips() {
+        let api = ServerApi::HackChatV2;
+        let with_trip = Chat {
+            trip: MaybeExist::Has(Trip("abc123".to_owned())),
+            ..chat("alice", Some(1), "hi")
+        };
+        assert_eq!(with_trip.into_json(api)["trip"].as_str(), Some("abc123"));
+
+        for trip in [MaybeExist::Unknown, MaybeExist::Not] {
+            let json = Chat {
+                trip,
+                ..chat("alice", Some(1), "hi")
+            }
+            .into_json(api);
+            assert!(!json.has_key("trip"));
+            assert_eq!(
+                Chat::from_json(json, api).unwrap().trip,
+                MaybeExist::Unknown
+            );
+        }
+    }
+
+    #[test]
+    fn join_error_from_warn() {
+        let taken = warn("Nickname taken");
+        assert_eq!(JoinError::try_from(&taken), Ok(JoinError::NickTaken));
+        let full = warn("This channel is full, try again later.");
+        assert_eq!(JoinError::try_from(&full), Ok(JoinError::ChannelFull));
+        let fast = warn("You are joining channels too fast. Wait a moment and try again.");
+        assert_eq!(JoinError::try_from(&fast), Ok(JoinError::RateLimited));
+
+        let unrelated = warn("You are sending too much text. Wait a moment and try again.");
+        assert_eq!(JoinError::try_from(&unrelated), Err(&unrelated));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    fn assert_round_trip<T>(value: T)
+    where
+        T: IntoJson + FromJson + Clone + PartialEq + std::fmt::Debug,
+    {
+        for &api in &[
+            ServerApi::HackChatV2,
+            ServerApi::HackChatPreV2,
+            ServerApi::HackChatLegacy,
+        ] {
+            assert_eq!(
+                T::from_json(value.clone().into_json(api), api),
+                Ok(value.clone()),
+                "{:?}",
+                api
+            );
+        }
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn every_server_command_round_trips() {
+        let trip = MaybeExist::Has(Trip("abc123".to_owned()));
+        let color = Some(Color {
+            r: 0x12,
+            g: 0xab,
+            b: 0xef,
+            a: None,
+        });
+
+        let user = OnlineSetUser {
+            channel: "lounge".to_owned(),
+            is_me: Some(true),
+            is_bot: Some(false),
+            nick: "alice".to_owned(),
+            trip: trip.clone(),
+            user_type: Some(UserType::Mod),
+            user_id: Some(5),
+            hash: "hash".to_owned(),
+            color,
+            level: Some(999999),
+        };
+        assert_round_trip(OnlineSet {
+            nicks: Some(vec!["alice".to_owned(), String::new()]),
+            users: Some(vec![
+                user.clone(),
+                OnlineSetUser::from_nick(String::new(), "lounge".to_owned()),
+            ]),
+            channel: Some("lounge".to_owned()),
+            time: Timestamp(1680000000000),
+        });
+        assert_round_trip(OnlineSet {
+            nicks: None,
+            users: None,
+            channel: None,
+            time: Timestamp(0),
+        });
+
+        let mut public = PublicChannels::default();
+        public.insert("lounge".to_owned(), 10);
+        public.insert("programming".to_owned(), 3);
+        assert_round_trip(Session {
+            public,
+            ..session(Some(true))
+        });
+        assert_round_trip(session(None));
+
+        assert_round_trip(Info {
+            text: "some info".to_owned(),
+            channel: Some("lounge".to_owned()),
+            time: Timestamp(1),
+        });
+        assert_round_trip(info(""));
+
+        assert_round_trip(Chat {
+            user_type: Some(UserType::Admin),
+            level: Some(9999999),
+            is_mod: true,
+            is_admin: true,
+            trip: trip.clone(),
+            id: Some(42),
+            channel: Some("lounge".to_owned()),
+            broadcast_channels: vec!["lounge".to_owned(), "programming".to_owned()],
+            ..chat("alice", Some(5), "hi")
+        });
+        assert_round_trip(Chat {
+            channel: Some("lounge".to_owned()),
+            trip: MaybeExist::Unknown,
+            ..chat("alice", None, "hi")
+        });
+        assert_round_trip(Chat {
+            trip: MaybeExist::Unknown,
+            ..chat("", None, "")
+        });
+
+        assert_round_trip(Whisper {
+            nick: "alice".to_owned(),
+            trip: trip.clone(),
+            text: "psst".to_owned(),
+            from: Some(5),
+            to: Some(6),
+            channel: Some("lounge".to_owned()),
+            time: Timestamp(1),
+        });
+        assert_round_trip(Whisper {
+            nick: String::new(),
+            trip: MaybeExist::Unknown,
+            text: String::new(),
+            from: None,
+            to: None,
+            channel: None,
+            time: Timestamp(0),
+        });
+
+        assert_round_trip(Captcha {
+            text: "captcha art".to_owned(),
+            channel: Some("lounge".to_owned()),
+        });
+        assert_round_trip(Captcha {
+            text: String::new(),
+            channel: None,
+        });
+
+        assert_round_trip(Emote {
+            trip: trip.clone(),
+            user_id: Some(5),
+            ..emote(Some("alice"), "@alice waves")
+        });
+        assert_round_trip(emote(None, "@alice waves"));
+
+        assert_round_trip(Invite {
+            channel: Some("lounge".to_owned()),
+            from: 5,
+            to: 6,
+            invite_channel: "secret".to_owned(),
+            time: Timestamp(1),
+        });
+        assert_round_trip(Invite {
+            channel: None,
+            from: 5,
+            to: 5,
+            invite_channel: String::new(),
+            time: Timestamp(0),
+        });
+
+        assert_round_trip(OnlineAdd {
+            channel: Some("lounge".to_owned()),
+            color,
+            hash: Some("hash".to_owned()),
+            is_bot: Some(true),
+            is_me: Some(false),
+            level: Some(100),
+            time: Timestamp(1),
+            trip: trip.clone(),
+            user_type: Some(UserType::User),
+            ..online_add("alice", Some(5))
+        });
+        assert_round_trip(online_add("", None));
+
+        assert_round_trip(OnlineRemove {
+            channel: Some("lounge".to_owned()),
+            nick: "alice".to_owned(),
+            time: Timestamp(1),
+            user_id: Some(5),
+        });
+        assert_round_trip(OnlineRemove {
+            channel: None,
+            nick: String::new(),
+            time: Timestamp(0),
+            user_id: None,
+        });
+
+        assert_round_trip(Warn {
+            channel: Some("lounge".to_owned()),
+            value: Some("alice".to_owned()),
+            ..warn("Nickname taken")
+        });
+        assert_round_trip(warn(""));
+
+        assert_round_trip(Stats {
+            users: Some(10),
+            channels: Some(2),
+            ips: Some(8),
+            uptime: Some(3600),
+            time: Some(Timestamp(1)),
+        });
+        assert_round_trip(Stats {
+            users: None,
+            channels: None,
+            ips: None,
+            uptime: None,
+            time: None,
+        });
+
+        assert_round_trip(ChannelChanged {
+            channel: "programming".to_owned(),
+            time: Timestamp(1),
+        });
+
+        assert_round_trip(UpdateUser {
+            user_id: 5,
+            channel: Some("lounge".to_owned()),
+            color,
+            trip,
+            level: Some(100),
+            nick: Some("alice".to_owned()),
+            time: Some(Timestamp(1)),
+        });
+        assert_round_trip(UpdateUser {
+            user_id: 5,
+            channel: None,
+            color: None,
+            trip: MaybeExist::Unknown,
+            level: None,
+            nick: None,
+            time: None,
+        });
+
+        assert_round_trip(Pong {
+            time: Some(Timestamp(1)),
+        });
+        assert_round_trip(Pong { time: None });
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn color_alpha_round_trips() {
+        let color = Some(Color::try_from("ff8800cc").unwrap());
+        assert_round_trip(OnlineAdd {
+            color,
+            ..online_add("alice", Some(5))
+        });
+        assert_round_trip(OnlineSetUser {
+            color,
+            ..OnlineSetUser::from_nick("alice".to_owned(), "lounge".to_owned())
+        });
+        assert_round_trip(UpdateUser {
+            user_id: 5,
+            channel: None,
+            color,
+            trip: MaybeExist::Unknown,
+            level: None,
+            nick: None,
+            time: None,
+        });
+    }
+
+    #[test]
+    fn online_set_consistency() {
+        let user = |nick: &str| OnlineSetUser::from_nick(nick.to_owned(), "lounge".to_owned());
+        let online_set = |nicks: Option<Vec<&str>>, users: Option<Vec<OnlineSetUser>>| OnlineSet {
+            nicks: nicks.map(|nicks| nicks.into_iter().map(str::to_owned).collect()),
+            users,
+            channel: Some("lounge".to_owned()),
+            time: Timestamp(0),
+        };
+
+        assert!(online_set(
+            Some(vec!["alice", "bob"]),
+            Some(vec![user("alice"), user("bob")])
+        )
+        .is_consistent());
+        assert!(!online_set(Some(vec!["alice", "bob"]), Some(vec![user("alice")])).is_consistent());
+        assert!(online_set(Some(vec!["alice"]), None).is_consistent());
+        assert!(online_set(None, Some(vec![user("alice")])).is_consistent());
+        assert!(online_set(None, None).is_consistent());
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn chat_channel_string_or_array() {
+        let chat = Chat::from_json(
+            json::object! { cmd: "chat", nick: "alice", text: "hi", channel: "lounge", time: 0 },
+            ServerApi::HackChatV2,
+        )
+        .unwrap();
+        assert_eq!(chat.channel.as_deref(), Some("lounge"));
+        assert!(chat.broadcast_channels.is_empty());
+        assert_eq!(chat.channels(), ["lounge"]);
+
+        let chat = Chat::from_json(
+            json::object! {
+                cmd: "chat",
+                nick: "alice",
+                text: "hi",
+                channel: ["lounge", "programming"],
+                time: 0,
+            },
+            ServerApi::HackChatV2,
+        )
+        .unwrap();
+        assert_eq!(chat.channel.as_deref(), Some("lounge"));
+        assert_eq!(chat.channels(), ["lounge", "programming"]);
+    }
+
+    #[test]
+    fn public_channels_by_prefix() {
+        let mut public = PublicChannels::default();
+        public.insert("programming".to_owned(), 3);
+        public.insert("Pixel".to_owned(), 7);
+        public.insert("prog".to_owned(), 3);
+        public.insert("lounge".to_owned(), 20);
+        let session = Session {
+            public,
+            ..session(None)
+        };
+
+        let pixel = "Pixel".to_owned();
+        let prog = "prog".to_owned();
+        let programming = "programming".to_owned();
+        assert_eq!(
+            session.public_starting_with("P"),
+            [(&pixel, 7), (&prog, 3), (&programming, 3)]
+        );
+        assert_eq!(session.public_starting_with("progr"), [(&programming, 3)]);
+        assert!(session.public_starting_with("x").is_empty());
+    }
+
+    #[test]
+    fn chat_mentions_self_ignoring_case() {
+        let mut users = Users::with_self(AccessUserId::Server(1));
+        users.apply_online_add(&online_add("Alice", Some(1)));
+        users.apply_online_add(&online_add("bob", Some(2)));
+
+        let mention = chat("bob", Some(2), "hey @aLiCe, look");
+        assert_eq!(mention.mentions(), ["aLiCe"]);
+        assert!(mention.mentions_self(&users));
+        assert!(!chat("bob", Some(2), "hey @alicex").mentions_self(&users));
+        assert!(!chat("alice", Some(1), "hey @bob").mentions_self(&users));
+        assert!(!mention.mentions_self(&Users::default()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn chat_serde_round_trip() {
+        let has = Chat {
+            user_type: Some(UserType::Mod),
+            channel: Some("lounge".to_owned()),
+            trip: MaybeExist::Has(Trip("abc123".to_owned())),
+            time: Timestamp(1680000000000),
+            id: Some(42),
+            ..chat("alice", Some(5), "hi")
+        };
+        let text = serde_json::to_string(&has).unwrap();
+        assert!(
+            text.contains(r#""trip":{"state":"has","value":"abc123"}"#),
+            "{}",
+            text
+        );
+        assert_eq!(serde_json::from_str::<Chat>(&text).unwrap(), has);
+
+        let not = Chat {
+            trip: MaybeExist::Not,
+            ..chat("bob", None, "")
+        };
+        let text = serde_json::to_string(&not).unwrap();
+        assert!(text.contains(r#""trip":{"state":"not"}"#), "{}", text);
+        assert_eq!(serde_json::from_str::<Chat>(&text).unwrap(), not);
+    }
+
+    #[test]
+    fn warn_rate_limit_cooldown() {
+        assert_eq!(
+            warn("You are being rate-limited, wait 30 seconds").rate_limit_cooldown(),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            warn("Slow down, 5 sec left").rate_limit_cooldown(),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(warn("Nickname taken").rate_limit_cooldown(), None);
+        assert_eq!(warn("wait a few seconds").rate_limit_cooldown(), None);
+    }
+
+    #[test]
+    fn filter_channel_uses_default_for_channelless() {
+        let in_lounge = Chat {
+            channel: Some("lounge".to_owned()),
+            ..chat("alice", Some(1), "in lounge")
+        };
+        let in_programming = Chat {
+            channel: Some("programming".to_owned()),
+            ..chat("bob", Some(2), "in programming")
+        };
+        let broadcast = Chat {
+            channel: Some("lounge".to_owned()),
+            broadcast_channels: vec!["lounge".to_owned(), "programming".to_owned()],
+            ..chat("admin", Some(3), "broadcast")
+        };
+        let channelless = chat("carol", None, "no channel");
+        let chats = [in_lounge, in_programming, broadcast, channelless];
+
+        let texts = |channel: &str, default_channel: Option<&str>| -> Vec<&str> {
+            filter_channel(&chats, channel, default_channel)
+                .into_iter()
+                .map(|chat| chat.text.as_str())
+                .collect()
+        };
+        assert_eq!(
+            texts("lounge", Some("lounge")),
+            ["in lounge", "broadcast", "no channel"]
+        );
+        assert_eq!(
+            texts("programming", Some("lounge")),
+            ["in programming", "broadcast"]
+        );
+        assert_eq!(texts("lounge", None), ["in lounge", "broadcast"]);
+        assert!(texts("elsewhere", Some("lounge")).is_empty());
+    }
+
+    #[test]
+    fn warn_offending_value_prefers_structured_field() {
+        let structured = Warn {
+            value: Some("Alice".to_owned()),
+            ..warn("Nickname taken")
+        };
+        assert_eq!(structured.offending_value(), Some("Alice"));
+
+        let both = Warn {
+            value: Some("Alice".to_owned()),
+            ..warn("The nick 'alice' is taken")
+        };
+        assert_eq!(both.offending_value(), Some("Alice"));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn warn_parses_value_field() {
+        let structured = Warn::from_json(
+            json::object! { cmd: "warn", text: "Nickname taken", value: "Alice", time: 0 },
+            ServerApi::HackChatV2,
+        )
+        .unwrap();
+        assert_eq!(structured.offending_value(), Some("Alice"));
+
+        let prose = Warn::from_json(
+            json::object! { cmd: "warn", text: "The nick 'Alice' is taken", time: 0 },
+            ServerApi::HackChatV2,
+        )
+        .unwrap();
+        assert_eq!(prose.value, None);
+        assert_eq!(prose.offending_value(), Some("Alice"));
+    }
+}
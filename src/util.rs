@@ -6,6 +6,13 @@ use json::JsonValue;
 /// Utility type where you have a val, not have a val, or be unknown as to which it is.
 /// Primarily for trips/hashes
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+// Tagged so that the form doesn't depend on `T`, such as `{"state":"has","value":"abc"}` and
+// `{"state":"unknown"}`.
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "state", content = "value", rename_all = "lowercase")
+)]
 pub enum MaybeExist<T> {
     /// We have the value
     Has(T),
@@ -49,6 +56,11 @@ impl<T> MaybeExist<T> {
         }
     }
 
+    /// Take the value out, leaving `Unknown` in its place.
+    pub fn take(&mut self) -> MaybeExist<T> {
+        std::mem::replace(self, MaybeExist::Unknown)
+    }
+
     pub fn as_unknown(&mut self) {
         *self = MaybeExist::Unknown;
     }
@@ -79,6 +91,30 @@ impl<T> MaybeExist<T> {
         }
     }
 
+    /// Keep the value only if it passes the predicate.
+    /// A value that fails becomes `Not`, since we know what they have and that it isn't acceptable.
+    /// `Unknown` and `Not` are left as they are.
+    pub fn filter<P>(self, predicate: P) -> MaybeExist<T>
+    where
+        P: FnOnce(&T) -> bool,
+    {
+        match self {
+            MaybeExist::Has(v) if predicate(&v) => MaybeExist::Has(v),
+            MaybeExist::Has(_) => MaybeExist::Not,
+            MaybeExist::Unknown => MaybeExist::Unknown,
+            MaybeExist::Not => MaybeExist::Not,
+        }
+    }
+
+    /// Like converting into an `Option`, but keeps which of the absent states it was.
+    pub fn into_result(self) -> Result<T, MaybeExistAbsent> {
+        match self {
+            MaybeExist::Has(v) => Ok(v),
+            MaybeExist::Unknown => Err(MaybeExistAbsent::Unknown),
+            MaybeExist::Not => Err(MaybeExistAbsent::Not),
+        }
+    }
+
     pub fn unwrap_or(self, default: T) -> T {
         match self {
             MaybeExist::Has(v) => v,
@@ -87,9 +123,18 @@ impl<T> MaybeExist<T> {
     }
 }
 
-impl<T> Into<Option<T>> for MaybeExist<T> {
-    fn into(self) -> Option<T> {
-        match self {
+/// Which way a `MaybeExist` was missing its value, from `MaybeExist::into_result`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum MaybeExistAbsent {
+    /// We don't know whether they have it.
+    Unknown,
+    /// They don't have it.
+    Not,
+}
+
+impl<T> From<MaybeExist<T>> for Option<T> {
+    fn from(value: MaybeExist<T>) -> Option<T> {
+        match value {
             MaybeExist::Has(v) => Some(v),
             _ => None,
         }
@@ -98,65 +143,293 @@ impl<T> Into<Option<T>> for MaybeExist<T> {
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ColorParseError {
+    /// The color was cut short, such as hex with fewer than three digits.
     UnexpectedEOF,
+    /// There were more hex digits than any color has.
     TooManyCharacters,
+    /// A character that isn't a hex digit.
+    InvalidDigit,
     ParseError(ParseIntError),
+    /// An `rgb()`/`rgba()` color had the wrong number of components, or an alpha that wasn't a
+    /// number from 0 to 1.
+    InvalidComponents,
 }
 impl From<ParseIntError> for ColorParseError {
     fn from(err: ParseIntError) -> Self {
         ColorParseError::ParseError(err)
     }
 }
-/// RGB color.
+/// RGB color, with an optional alpha.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Color {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    /// `None` when the color was given without one, which is treated as opaque.
+    pub a: Option<u8>,
+}
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(255, 255, 255);
+    pub const RED: Color = Color::rgb(255, 0, 0);
+    pub const GREEN: Color = Color::rgb(0, 128, 0);
+    pub const BLUE: Color = Color::rgb(0, 0, 255);
+
+    /// The sixteen basic css color names, which is all that `from_name` knows of.
+    const NAMES: [(&'static str, Color); 16] = [
+        ("black", Color::BLACK),
+        ("silver", Color::rgb(192, 192, 192)),
+        ("gray", Color::rgb(128, 128, 128)),
+        ("white", Color::WHITE),
+        ("maroon", Color::rgb(128, 0, 0)),
+        ("red", Color::RED),
+        ("purple", Color::rgb(128, 0, 128)),
+        ("fuchsia", Color::rgb(255, 0, 255)),
+        ("green", Color::GREEN),
+        ("lime", Color::rgb(0, 255, 0)),
+        ("olive", Color::rgb(128, 128, 0)),
+        ("yellow", Color::rgb(255, 255, 0)),
+        ("navy", Color::rgb(0, 0, 128)),
+        ("blue", Color::BLUE),
+        ("teal", Color::rgb(0, 128, 128)),
+        ("aqua", Color::rgb(0, 255, 255)),
+    ];
+
+    /// An opaque color, without an alpha.
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b, a: None }
+    }
+
+    /// Look up one of the basic css color names, such as `red` or `navy`, ignoring case.
+    pub fn from_name(name: &str) -> Option<Color> {
+        Color::NAMES
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .map(|(_, color)| *color)
+    }
+
+    /// Parse a color in either hex or the css functional form, like `rgb(255, 0, 128)` or
+    /// `rgba(0, 0, 0, 0.5)`.
+    pub fn from_css(text: &str) -> Result<Color, ColorParseError> {
+        let text = text.trim();
+        let (components, expected) = if let Some(rest) = text.strip_prefix("rgba(") {
+            (rest, 4)
+        } else if let Some(rest) = text.strip_prefix("rgb(") {
+            (rest, 3)
+        } else {
+            return Color::try_from(text);
+        };
+        let components = components
+            .strip_suffix(')')
+            .ok_or(ColorParseError::UnexpectedEOF)?;
+
+        let components: Vec<&str> = components.split(',').map(str::trim).collect();
+        if components.len() != expected {
+            return Err(ColorParseError::InvalidComponents);
+        }
+        let a = match components.get(3).map(|alpha| alpha.parse::<f32>()) {
+            Some(Ok(alpha)) if (0.0..=1.0).contains(&alpha) => Some((alpha * 255.0).round() as u8),
+            Some(_) => return Err(ColorParseError::InvalidComponents),
+            None => None,
+        };
+
+        Ok(Color {
+            r: components[0].parse()?,
+            g: components[1].parse()?,
+            b: components[2].parse()?,
+            a,
+        })
+    }
+
+    /// The color as six lowercase hex digits, without a `#`, as the server expects it.
+    /// This is the canonical form, whatever case or length the color was parsed from.
+    /// Any alpha is left out, as the server doesn't accept it.
+    pub fn to_hex(&self) -> String {
+        format!("{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+
+    /// Like `to_hex`, but with eight digits when there is an alpha, so that it parses back into
+    /// the same color.
+    pub fn to_hex_with_alpha(&self) -> String {
+        match self.a {
+            Some(a) => format!("{}{:02x}", self.to_hex(), a),
+            None => self.to_hex(),
+        }
+    }
+
+    pub fn to_array(&self) -> [u8; 3] {
+        [self.r, self.g, self.b]
+    }
+
+    /// The color as floats from 0 to 1, with an alpha of 1 if there is none.
+    pub fn to_srgb_f32(&self) -> [f32; 4] {
+        [
+            f32::from(self.r) / 255.0,
+            f32::from(self.g) / 255.0,
+            f32::from(self.b) / 255.0,
+            f32::from(self.a.unwrap_or(255)) / 255.0,
+        ]
+    }
+
+    /// The relative luminance of the color, from 0 for black to 1 for white.
+    pub fn luminance(&self) -> f32 {
+        let [r, g, b, _] = self.to_linear_f32();
+        0.2126 * r + 0.7152 * g + 0.0722 * b
+    }
+
+    /// The color converted from sRGB into linear space, as graphics apis tend to want.
+    /// Alpha is not converted.
+    pub fn to_linear_f32(&self) -> [f32; 4] {
+        fn linearize(c: f32) -> f32 {
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        }
+
+        let [r, g, b, a] = self.to_srgb_f32();
+        [linearize(r), linearize(g), linearize(b), a]
+    }
+}
+/// Css hex, as `#rrggbb`, or `#rrggbbaa` when there is an alpha.
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.to_hex_with_alpha())
+    }
+}
+impl From<Color> for (u8, u8, u8) {
+    fn from(color: Color) -> Self {
+        (color.r, color.g, color.b)
+    }
+}
+impl From<(u8, u8, u8)> for Color {
+    fn from((r, g, b): (u8, u8, u8)) -> Self {
+        Color::rgb(r, g, b)
+    }
 }
 impl TryFrom<&str> for Color {
     type Error = ColorParseError;
-    // TODO: handle if the values are unicode and the slicing partway through them is incorrect.
     fn try_from(text: &str) -> Result<Self, Self::Error> {
-        use std::cmp::Ordering;
         // This shouldn't appear in the string, but we might as well handle it.
         let text = text.trim_start_matches('#');
+        // Only ascii hex digits are allowed, which also makes the slicing below safe, as every
+        // character is then a single byte.
+        if !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(ColorParseError::InvalidDigit);
+        }
 
         // Compare the length to see if its valid for parsing.
+        // Each component is either one digit, which is doubled as in css so `f0a` is `ff00aa`,
+        // or two digits. Four or eight digits have an alpha at the end.
         let len = text.len();
-        match len.cmp(&6) {
+        let width = match len {
+            3 | 4 => 1,
+            6 | 8 => 2,
             // Too many characters.
-            // TODO: should this support RGBA?
-            Ordering::Greater => Err(ColorParseError::UnexpectedEOF),
-            // Single byte RGB.
-            Ordering::Less if len == 3 => {
-                let red = u8::from_str_radix(&text[0..1], 16)?;
-                let green = u8::from_str_radix(&text[1..2], 16)?;
-                let blue = u8::from_str_radix(&text[2..3], 16)?;
-
-                Ok(Color {
-                    r: red,
-                    g: green,
-                    b: blue,
-                })
-            }
+            _ if len > 6 => return Err(ColorParseError::TooManyCharacters),
             // Not enough characters to consider.
-            Ordering::Less => Err(ColorParseError::TooManyCharacters),
-            // Two byte RGB
-            _ => {
-                // Sanity: these indices are
-                let red = u8::from_str_radix(&text[0..2], 16)?;
-                let green = u8::from_str_radix(&text[2..4], 16)?;
-                let blue = u8::from_str_radix(&text[4..6], 16)?;
-
-                Ok(Color {
-                    r: red,
-                    g: green,
-                    b: blue,
-                })
+            _ => return Err(ColorParseError::UnexpectedEOF),
+        };
+        let component = |index: usize| -> Result<u8, ParseIntError> {
+            let value = u8::from_str_radix(&text[index * width..(index + 1) * width], 16)?;
+            Ok(if width == 1 { value * 17 } else { value })
+        };
+
+        Ok(Color {
+            r: component(0)?,
+            g: component(1)?,
+            b: component(2)?,
+            a: if len / width == 4 {
+                Some(component(3)?)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// Problems with a client command that was constructed through one of the checked constructors.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CommandError {
+    /// The text was empty or only consisted of whitespace.
+    EmptyText,
+    /// The text was longer than the server would accept.
+    TextTooLong,
+    /// The server api requires a channel to be specified, but there was none.
+    MissingChannel,
+    /// The nickname was empty, too long, or contained characters other than letters, digits,
+    /// and underscores.
+    InvalidNick,
+    /// The channel name was longer than the server would accept.
+    ChannelTooLong,
+}
+
+/// The `?channel` part of the site's url that leads to the channel, with any characters that
+/// can't appear in a url percent-encoded.
+pub fn channel_query(channel: &str) -> String {
+    let mut query = String::with_capacity(channel.len() + 1);
+    query.push('?');
+    for byte in channel.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                query.push(byte as char)
+            }
+            _ => query.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    query
+}
+
+/// Escape the characters that are special in html, the counterpart of `decode_entities`.
+pub fn escape_entities(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// Decode the few html entities that some servers escape message text with.
+/// This is done in a single pass, so `&amp;lt;` becomes `&lt;` rather than `<`. Anything that
+/// isn't one of the known entities is left as is.
+pub fn decode_entities(text: &str) -> String {
+    const ENTITIES: [(&str, char); 7] = [
+        ("&lt;", '<'),
+        ("&gt;", '>'),
+        ("&amp;", '&'),
+        ("&quot;", '"'),
+        ("&apos;", '\''),
+        ("&#39;", '\''),
+        ("&#x27;", '\''),
+    ];
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('&') {
+        decoded.push_str(&rest[..index]);
+        rest = &rest[index..];
+        match ENTITIES.iter().find(|(entity, _)| rest.starts_with(entity)) {
+            Some((entity, character)) => {
+                decoded.push(*character);
+                rest = &rest[entity.len()..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
             }
         }
     }
+    decoded.push_str(rest);
+    decoded
 }
 
 /// Convert the thing (usually a command) into json.
@@ -164,30 +437,158 @@ impl TryFrom<&str> for Color {
 pub trait IntoJson {
     /// The server format only applies when deciding how to format the data inside
     /// If you're using a command that's only for a specific format, then it will still be created.
+    /// For commands, the result must always have a `cmd` field equal to `Command::CMD`, whatever
+    /// the server api.
+    ///
+    /// Server commands that implement both this and `FromJson` parse back into the same value for
+    /// every server api, except that:
+    /// - A `MaybeExist::Not` trip is left out like an unknown one, so it comes back as `Unknown`,
+    ///   since the servers don't distinguish the two either.
+    /// - `OnlineSet` writes its channel to both `channel` and `text`, as it is read from `text`.
     fn into_json(self, server_api: crate::ServerApi) -> JsonValue;
 }
+/// Serialize commands in order, one frame each.
+/// hack.chat expects every websocket frame to hold a single json object, so the commands can't be
+/// sent together as an array.
+#[cfg(feature = "json_parsing")]
+pub fn serialize_batch<C, I>(cmds: I, server_api: crate::ServerApi) -> Vec<String>
+where
+    C: IntoJson,
+    I: IntoIterator<Item = C>,
+{
+    cmds.into_iter()
+        .map(|cmd| cmd.into_json(server_api).dump())
+        .collect()
+}
+
 /// Mark a command, and the name of its CMD property.
 pub trait Command {
     const CMD: &'static str;
+
+    /// Whether the json is this command, judging only by its `cmd` field.
+    #[cfg(feature = "json_parsing")]
+    fn matches(json: &JsonValue) -> bool {
+        peek_cmd(json) == Some(Self::CMD)
+    }
 }
 /// Marker trait for commands sent from the client
-pub trait ClientCommand: Command {}
+pub trait ClientCommand: Command {
+    /// Push any problems with the command that would cause the server to reject it.
+    fn check(&self, _server_api: crate::ServerApi, _errors: &mut Vec<CommandError>) {}
+}
+
+/// Check a command for anything that the server would reject, before it is sent.
+pub fn validate_client<C: ClientCommand>(
+    cmd: &C,
+    server_api: crate::ServerApi,
+) -> Result<(), Vec<CommandError>> {
+    let mut errors = Vec::new();
+    cmd.check(server_api, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
 /// Marker trait for commands sent by the server
 pub trait ServerCommand: Command {}
 
+/// Something that happened at a known time, for sorting and merging timelines.
+pub trait TimedEvent {
+    fn time(&self) -> crate::Timestamp;
+}
+
+/// Something sent by a user, so that messages of different kinds can be rendered the same way.
+pub trait Authored {
+    fn author_nick(&self) -> Option<&str>;
+    fn author_trip(&self) -> MaybeExist<&crate::Trip>;
+    fn author_id(&self) -> Option<crate::UserId>;
+}
+
 #[cfg(feature = "json_parsing")]
 #[derive(Debug, Clone, PartialEq)]
 pub enum FromJsonError {
     InvalidStructure,
     InvalidField(&'static str),
     InvalidCommandField(&'static str),
+    /// The field was a string, but not one holding a valid number.
+    InvalidNumber(&'static str),
+}
+/// The error doesn't say which field failed to parse, so it is named `time`, the only field that
+/// servers are known to send numbers as strings in. Use `map_err` to name any other field.
+#[cfg(feature = "json_parsing")]
+impl From<ParseIntError> for FromJsonError {
+    fn from(_: ParseIntError) -> Self {
+        FromJsonError::InvalidNumber(crate::id::TIME)
+    }
+}
+#[cfg(feature = "json_parsing")]
+impl FromJsonError {
+    /// A longer hint at what likely went wrong, for debugging.
+    pub fn explain(&self) -> &'static str {
+        match self {
+            FromJsonError::InvalidStructure => {
+                "the json wasn't shaped like the command, such as not being an object"
+            }
+            FromJsonError::InvalidField(_) => {
+                "a field was missing or had the wrong type; the server api may not send it"
+            }
+            FromJsonError::InvalidCommandField(_) => {
+                "the 'cmd' field didn't match the expected command; you may be parsing the wrong type"
+            }
+            FromJsonError::InvalidNumber(_) => {
+                "a field that should be a number was a string that couldn't be parsed as one"
+            }
+        }
+    }
 }
 /// For extracting a command from the json sent by the server.
 #[cfg(feature = "json_parsing")]
 pub trait FromJson: Sized {
     fn from_json(json: JsonValue, server_api: crate::ServerApi) -> Result<Self, FromJsonError>;
+
+    /// Parse the value without requiring it to have a `cmd` field, for when it is an element
+    /// of an array within another command.
+    fn from_json_nested(
+        json: JsonValue,
+        server_api: crate::ServerApi,
+    ) -> Result<Self, FromJsonError> {
+        Self::from_json(json, server_api)
+    }
 }
 
+/// Note that the server sent a command that we don't know of.
+/// With the `tracing` feature this emits a debug event, so that new protocol commands get noticed
+/// without being treated as errors. Otherwise it does nothing.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub fn log_unknown_command(cmd: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(cmd, "unknown command from server");
+}
+
+/// Get the `cmd` field of a message without parsing the rest of it.
+#[cfg(feature = "json_parsing")]
+pub fn peek_cmd(value: &JsonValue) -> Option<&str> {
+    value[crate::id::CMD].as_str()
+}
+
+/// The channel names in a `channel` field, which is usually a single string but is an array for
+/// messages broadcast to several channels. Anything else gives an empty list, as do array
+/// elements that aren't strings.
+#[cfg(feature = "json_parsing")]
+pub fn as_channel_list(value: &JsonValue) -> Vec<crate::Channel> {
+    match value {
+        JsonValue::Array(channels) => channels
+            .iter()
+            .filter_map(|channel| channel.as_str().map(crate::Channel::from))
+            .collect(),
+        _ => value
+            .as_str()
+            .map(crate::Channel::from)
+            .into_iter()
+            .collect(),
+    }
+}
 /// Utility function for converting to an array, as the json lib does not supply it
 #[cfg(feature = "json_parsing")]
 pub fn as_array(value: JsonValue) -> Option<Vec<JsonValue>> {
@@ -204,3 +605,264 @@ pub fn as_object(value: JsonValue) -> Option<json::object::Object> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_chat_v2() {
+        use crate::{client, ServerApi};
+
+        let chat = client::Chat {
+            channel: Some("lounge".to_owned()),
+            text: "hello".to_owned(),
+        };
+        assert_eq!(validate_client(&chat, ServerApi::HackChatV2), Ok(()));
+
+        let chat = client::Chat {
+            channel: None,
+            text: "  ".to_owned(),
+        };
+        assert_eq!(
+            validate_client(&chat, ServerApi::HackChatV2),
+            Err(vec![CommandError::EmptyText, CommandError::MissingChannel])
+        );
+    }
+
+    #[test]
+    fn color_tuple_and_array() {
+        let color = Color::from((1, 2, 3));
+        assert_eq!(color, Color::rgb(1, 2, 3));
+        assert_eq!(<(u8, u8, u8)>::from(color), (1, 2, 3));
+        assert_eq!(color.to_array(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn maybe_exist_filter() {
+        let is_short = |value: &&str| value.len() <= 6;
+        assert_eq!(
+            MaybeExist::Has("abc").filter(is_short),
+            MaybeExist::Has("abc")
+        );
+        assert_eq!(
+            MaybeExist::Has("abcdefgh").filter(is_short),
+            MaybeExist::Not
+        );
+        assert_eq!(
+            MaybeExist::<&str>::Unknown.filter(is_short),
+            MaybeExist::Unknown
+        );
+        assert_eq!(MaybeExist::<&str>::Not.filter(is_short), MaybeExist::Not);
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn command_matches_by_cmd() {
+        use crate::server;
+
+        let chat = json::object! { cmd: "chat", nick: "alice", text: "hi" };
+        assert!(server::Chat::matches(&chat));
+        assert!(!server::Emote::matches(&chat));
+        assert!(!server::Chat::matches(&json::object! { nick: "alice" }));
+    }
+
+    #[test]
+    fn mid_gray_to_linear() {
+        let gray = Color::rgb(128, 128, 128);
+        let [r, g, b, a] = gray.to_linear_f32();
+        // ((128 / 255 + 0.055) / 1.055) ^ 2.4
+        let expected = 0.215_860_5;
+        for channel in [r, g, b] {
+            assert!((channel - expected).abs() < 1e-5, "{}", channel);
+        }
+        assert_eq!(a, 1.0);
+
+        let [r, _, _, a] = Color { a: Some(0), ..gray }.to_srgb_f32();
+        assert!((r - 128.0 / 255.0).abs() < 1e-6);
+        assert_eq!(a, 0.0);
+    }
+
+    #[test]
+    fn endpoints_and_channel_query() {
+        use crate::ServerApi;
+
+        for server_api in [
+            ServerApi::HackChatV2,
+            ServerApi::HackChatPreV2,
+            ServerApi::HackChatLegacy,
+        ] {
+            assert_eq!(server_api.default_endpoint(), "wss://hack.chat/chat-ws");
+        }
+
+        assert_eq!(channel_query("lounge"), "?lounge");
+        assert_eq!(channel_query("my room/é"), "?my%20room%2F%C3%A9");
+    }
+
+    #[test]
+    fn css_functional_colors() {
+        assert_eq!(
+            Color::from_css("rgb(255,0,128)"),
+            Ok(Color::rgb(255, 0, 128))
+        );
+        assert_eq!(
+            Color::from_css(" rgb( 255 , 0 , 128 ) "),
+            Ok(Color::rgb(255, 0, 128))
+        );
+        assert_eq!(
+            Color::from_css("rgba(0,0,0,0.5)"),
+            Ok(Color {
+                a: Some(128),
+                ..Color::BLACK
+            })
+        );
+        assert_eq!(
+            Color::from_css("rgba(0,0,0,2)"),
+            Err(ColorParseError::InvalidComponents)
+        );
+        assert_eq!(
+            Color::from_css("rgb(0,0)"),
+            Err(ColorParseError::InvalidComponents)
+        );
+        assert_eq!(Color::from_css("#ff0080"), Ok(Color::rgb(255, 0, 128)));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn serialize_join_then_chat() {
+        use crate::{client, client::ClientMessage, ServerApi};
+
+        let frames = serialize_batch(
+            vec![
+                ClientMessage::Join(client::Join {
+                    nick: "alice".to_owned(),
+                    channel: "lounge".to_owned(),
+                    password: None,
+                }),
+                ClientMessage::Chat(client::Chat {
+                    channel: Some("lounge".to_owned()),
+                    text: "hi".to_owned(),
+                }),
+            ],
+            ServerApi::HackChatV2,
+        );
+        assert_eq!(
+            frames,
+            [
+                r#"{"cmd":"join","channel":"lounge","nick":"alice"}"#,
+                r#"{"cmd":"chat","text":"hi","channel":"lounge"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn maybe_exist_take() {
+        let mut trip = MaybeExist::Has("abc");
+        assert_eq!(trip.take(), MaybeExist::Has("abc"));
+        assert_eq!(trip, MaybeExist::Unknown);
+
+        let mut trip = MaybeExist::<&str>::Not;
+        assert_eq!(trip.take(), MaybeExist::Not);
+        assert_eq!(trip, MaybeExist::Unknown);
+    }
+
+    #[test]
+    fn hex_case_is_normalized() {
+        let expected = Color::rgb(0xff, 0x00, 0xaa);
+        for text in ["FF00AA", "ff00aa", "Ff00aA", "#FF00AA", "F0A"] {
+            let color = Color::try_from(text).unwrap();
+            assert_eq!(color, expected);
+            assert_eq!(color.to_hex(), "ff00aa");
+        }
+
+        let color = Color::try_from("FF00AA80").unwrap();
+        assert_eq!(color.to_hex_with_alpha(), "ff00aa80");
+    }
+
+    #[test]
+    fn color_length_errors() {
+        assert_eq!(
+            Color::try_from("1234567"),
+            Err(ColorParseError::TooManyCharacters)
+        );
+        assert_eq!(Color::try_from("12"), Err(ColorParseError::UnexpectedEOF));
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn from_json_error_explanations() {
+        for err in &[
+            FromJsonError::InvalidStructure,
+            FromJsonError::InvalidField(crate::id::NICK),
+            FromJsonError::InvalidCommandField(crate::id::CMD),
+            FromJsonError::InvalidNumber(crate::id::TIME),
+        ] {
+            assert!(!err.explain().is_empty(), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn color_rejects_non_hex() {
+        assert_eq!(
+            Color::try_from("café12"),
+            Err(ColorParseError::InvalidDigit)
+        );
+        assert_eq!(Color::try_from("ééé"), Err(ColorParseError::InvalidDigit));
+        assert_eq!(
+            Color::try_from("12345g"),
+            Err(ColorParseError::InvalidDigit)
+        );
+        assert_eq!(Color::try_from("+1+"), Err(ColorParseError::InvalidDigit));
+    }
+
+    #[test]
+    fn color_display() {
+        assert_eq!(Color::rgb(255, 0, 16).to_string(), "#ff0010");
+        let color = Color {
+            a: Some(0x80),
+            ..Color::rgb(255, 0, 16)
+        };
+        assert_eq!(color.to_string(), "#ff001080");
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn channel_list_from_string_or_array() {
+        assert_eq!(as_channel_list(&"lounge".into()), ["lounge"]);
+        assert_eq!(
+            as_channel_list(&json::array!["lounge", 5, "programming"]),
+            ["lounge", "programming"]
+        );
+        assert!(as_channel_list(&JsonValue::Null).is_empty());
+    }
+
+    #[test]
+    fn color_from_name() {
+        assert_eq!(Color::from_name("red"), Some(Color::RED));
+        assert_eq!(Color::from_name("Navy"), Some(Color::rgb(0, 0, 128)));
+        assert_eq!(Color::from_name("notacolor"), None);
+    }
+
+    #[test]
+    fn maybe_exist_into_result() {
+        assert_eq!(MaybeExist::Has(5).into_result(), Ok(5));
+        assert_eq!(
+            MaybeExist::<u8>::Unknown.into_result(),
+            Err(MaybeExistAbsent::Unknown)
+        );
+        assert_eq!(
+            MaybeExist::<u8>::Not.into_result(),
+            Err(MaybeExistAbsent::Not)
+        );
+    }
+
+    #[cfg(feature = "json_parsing")]
+    #[test]
+    fn parse_int_error_into_from_json_error() {
+        let err = "soon".parse::<u64>().unwrap_err();
+        assert_eq!(
+            FromJsonError::from(err),
+            FromJsonError::InvalidNumber(crate::id::TIME)
+        );
+    }
+}